@@ -0,0 +1,230 @@
+//! Encoding and decoding many documents at once on a thread pool.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+use crate::{
+    CoreBPE, DecodeError, DisallowedSpecialError, Rank, SpecialTokenSet, UnknownTokenPolicy,
+};
+
+#[derive(Debug)]
+pub enum BatchError {
+    /// The thread pool couldn't be started, e.g. because the OS won't give us any more threads.
+    ThreadPool(ThreadPoolBuildError),
+    Decode(DecodeError),
+    DisallowedSpecial(DisallowedSpecialError),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::ThreadPool(e) => write!(f, "Failed to start the thread pool: {}", e),
+            BatchError::Decode(e) => write!(f, "{}", e),
+            BatchError::DisallowedSpecial(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl From<ThreadPoolBuildError> for BatchError {
+    fn from(e: ThreadPoolBuildError) -> Self {
+        BatchError::ThreadPool(e)
+    }
+}
+
+impl From<DecodeError> for BatchError {
+    fn from(e: DecodeError) -> Self {
+        BatchError::Decode(e)
+    }
+}
+
+impl From<DisallowedSpecialError> for BatchError {
+    fn from(e: DisallowedSpecialError) -> Self {
+        BatchError::DisallowedSpecial(e)
+    }
+}
+
+/// The last thread pool with a different size than rayon's global pool. Starting a pool means
+/// starting all of its threads, so it's kept for later batches with the same `num_threads`. Any
+/// other size replaces it, and its threads exit once the batches still using it are done.
+static POOL: Mutex<Option<Arc<ThreadPool>>> = Mutex::new(None);
+
+/// The pool to run a batch on, or `None` for rayon's global pool.
+fn thread_pool(
+    cache: &Mutex<Option<Arc<ThreadPool>>>,
+    num_threads: usize,
+) -> Result<Option<Arc<ThreadPool>>, ThreadPoolBuildError> {
+    if num_threads == 0 || num_threads == rayon::current_num_threads() {
+        return Ok(None);
+    }
+    // Nothing can be left half done while this is locked, so a poisoned lock is fine to use
+    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(pool) = cache.as_ref() {
+        if pool.current_num_threads() == num_threads {
+            return Ok(Some(Arc::clone(pool)));
+        }
+    }
+    let pool = Arc::new(ThreadPoolBuilder::new().num_threads(num_threads).build()?);
+    *cache = Some(Arc::clone(&pool));
+    Ok(Some(pool))
+}
+
+/// Applies `f` to every item on a pool of `num_threads` threads, keeping the results in order.
+/// `num_threads == 0` means one thread per CPU.
+fn map_batch<T, R, F>(items: &[T], num_threads: usize, f: F) -> Result<Vec<R>, BatchError>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    let run = || items.par_iter().map(f).collect();
+    Ok(match thread_pool(&POOL, num_threads)? {
+        Some(pool) => pool.install(run),
+        None => run(),
+    })
+}
+
+impl CoreBPE {
+    /// Same as calling `encode_ordinary` on every text, but spread across `num_threads` threads
+    /// (or one per CPU if that's 0).
+    pub fn encode_ordinary_batch(
+        &self,
+        texts: &[&str],
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, BatchError> {
+        map_batch(texts, num_threads, |text| self.encode_ordinary(text))
+    }
+
+    /// Same as calling `encode` on every text, but spread across `num_threads` threads (or one per
+    /// CPU if that's 0).
+    pub fn encode_batch(
+        &self,
+        texts: &[&str],
+        allowed_special: &HashSet<&str>,
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, BatchError> {
+        map_batch(texts, num_threads, |text| {
+            self.encode(text, allowed_special)
+        })
+    }
+
+    /// Same as calling `encode_checked` on every text. The error is for the first text that has
+    /// a disallowed special token.
+    pub fn encode_batch_checked(
+        &self,
+        texts: &[&str],
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, BatchError> {
+        map_batch(texts, num_threads, |text| {
+            self.encode_checked(text, allowed_special, disallowed_special)
+        })?
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(BatchError::from)
+    }
+
+    /// Same as calling `decode_bytes` on every list of tokens, but spread across `num_threads`
+    /// threads (or one per CPU if that's 0).
+    pub fn decode_batch(
+        &self,
+        batch: &[Vec<Rank>],
+        num_threads: usize,
+    ) -> Result<Vec<Vec<u8>>, BatchError> {
+        self.decode_batch_with_policy(batch, UnknownTokenPolicy::Raise, num_threads)
+    }
+
+    /// Same as calling `decode_bytes_with_policy` on every list of tokens. The error is for the
+    /// first list of tokens that has an unknown token.
+    pub fn decode_batch_with_policy(
+        &self,
+        batch: &[Vec<Rank>],
+        unknown: UnknownTokenPolicy,
+        num_threads: usize,
+    ) -> Result<Vec<Vec<u8>>, BatchError> {
+        let indexed: Vec<(usize, &Vec<Rank>)> = batch.iter().enumerate().collect();
+        map_batch(&indexed, num_threads, |&(batch_index, tokens)| {
+            self._decode_checked(tokens, unknown)
+                .map_err(|e| DecodeError {
+                    batch_index: Some(batch_index),
+                    ..e
+                })
+        })?
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(BatchError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use super::{thread_pool, BatchError};
+    use crate::tests::setup_core_bpe;
+    use crate::SpecialTokenSet;
+
+    #[test]
+    fn test_batch() {
+        let bpe = setup_core_bpe();
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let texts: Vec<String> = (0..100)
+            .map(|i| format!("document {} <|endoftext|> {}", i, "wörd ".repeat(i)))
+            .collect();
+        let texts: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+
+        for num_threads in [0, 1, 4] {
+            let batch = bpe
+                .encode_batch(&texts, &allowed_special, num_threads)
+                .unwrap();
+            for (text, tokens) in texts.iter().zip(&batch) {
+                assert_eq!(tokens, &bpe.encode(text, &allowed_special));
+            }
+            let batch_ordinary = bpe.encode_ordinary_batch(&texts, num_threads).unwrap();
+            for (text, tokens) in texts.iter().zip(&batch_ordinary) {
+                assert_eq!(tokens, &bpe.encode_ordinary(text));
+            }
+            let decoded = bpe.decode_batch(&batch, num_threads).unwrap();
+            for (text, bytes) in texts.iter().zip(decoded) {
+                assert_eq!(bytes, text.as_bytes());
+            }
+
+            let allowed = SpecialTokenSet::Only(&allowed_special);
+            let checked = bpe
+                .encode_batch_checked(&texts, allowed, SpecialTokenSet::All, num_threads)
+                .unwrap();
+            assert_eq!(checked, batch);
+            let mut texts = texts.clone();
+            texts[60] = "<|fim_prefix|> sixty";
+            texts[40] = "forty <|fim_prefix|>";
+            match bpe.encode_batch_checked(&texts, allowed, SpecialTokenSet::All, num_threads) {
+                Err(BatchError::DisallowedSpecial(e)) => assert_eq!(e.offset, 6),
+                ret => panic!("expected a disallowed special token, got {:?}", ret),
+            }
+        }
+    }
+
+    #[test]
+    fn test_thread_pool() {
+        let cache = Mutex::new(None);
+        let num_threads = rayon::current_num_threads();
+        assert!(thread_pool(&cache, 0).unwrap().is_none());
+        assert!(thread_pool(&cache, num_threads).unwrap().is_none());
+
+        let pool = thread_pool(&cache, num_threads + 1).unwrap().unwrap();
+        assert_eq!(pool.current_num_threads(), num_threads + 1);
+        let same = thread_pool(&cache, num_threads + 1).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&pool, &same));
+        // Only the last pool is kept
+        thread_pool(&cache, num_threads + 2).unwrap().unwrap();
+        drop(same);
+        assert_eq!(Arc::strong_count(&pool), 1);
+    }
+}
@@ -0,0 +1,220 @@
+//! Splitting text into chunks of a bounded number of tokens, e.g. for retrieval.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::CoreBPE;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkError {
+    /// Chunks couldn't make progress if they repeated all of the chunk before them.
+    OverlapTooLarge { overlap: usize, max_tokens: usize },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::OverlapTooLarge {
+                overlap,
+                max_tokens,
+            } => write!(
+                f,
+                "overlap must be smaller than max_tokens, but {} >= {}",
+                overlap, max_tokens
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// Where `chunk_text` prefers to cut. If there is no such place within a chunk, it falls back to
+/// a regex piece boundary, and then to any token boundary that isn't inside a character.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkBoundary {
+    /// Any token boundary that isn't inside a character
+    Token,
+    /// Between two regex pieces, which is where `encode` never merges across
+    #[default]
+    Piece,
+    /// Right after a newline
+    Newline,
+    /// Right after the end of a sentence or a newline
+    Sentence,
+}
+
+impl ChunkBoundary {
+    /// `pos` is always at a token boundary
+    fn matches(self, text: &str, pos: usize, is_piece_end: bool) -> bool {
+        if !text.is_char_boundary(pos) {
+            return false;
+        }
+        match self {
+            ChunkBoundary::Token => true,
+            ChunkBoundary::Piece => is_piece_end,
+            // Not necessarily a piece boundary, since e.g. with cl100k_base "。" starts a piece
+            ChunkBoundary::Newline => text[..pos].ends_with('\n'),
+            ChunkBoundary::Sentence => text[..pos].ends_with('\n') || is_sentence_end(text, pos),
+        }
+    }
+}
+
+fn is_sentence_end(text: &str, pos: usize) -> bool {
+    let before = text[..pos].trim_end_matches(['"', '\'', ')', ']', '”', '’']);
+    match before.chars().next_back() {
+        Some('.' | '!' | '?') => text[pos..].starts_with(char::is_whitespace),
+        Some('。' | '！' | '？') => true,
+        _ => false,
+    }
+}
+
+impl CoreBPE {
+    /// Splits `text` into chunks of at most `max_tokens` tokens of `encode_ordinary(text)`, where
+    /// each chunk repeats the last `overlap` tokens of the chunk before it. Returns the chunks and
+    /// their byte ranges in `text`.
+    ///
+    /// Chunks end at the last `boundary` that fits, and never inside a character. The only case
+    /// where a chunk has more than `max_tokens` tokens is when a single character takes more
+    /// tokens than that. Note that encoding a chunk on its own can give slightly different tokens
+    /// than the ones it was counted with, since it lacks the text around it.
+    ///
+    /// Returns an error if `overlap >= max_tokens`.
+    #[allow(clippy::type_complexity)]
+    pub fn chunk_text<'t>(
+        &self,
+        text: &'t str,
+        max_tokens: usize,
+        overlap: usize,
+        boundary: ChunkBoundary,
+    ) -> Result<Vec<(&'t str, (usize, usize))>, ChunkError> {
+        if overlap >= max_tokens {
+            return Err(ChunkError::OverlapTooLarge {
+                overlap,
+                max_tokens,
+            });
+        }
+        let (_, offsets) = self.encode_with_offsets(text, &HashSet::new());
+        let regex = self._get_regex();
+        let piece_ends: HashSet<usize> =
+            self._pieces(&regex, text).map(|range| range.end).collect();
+        let can_cut = |kind: ChunkBoundary, end: usize| {
+            let pos = offsets[end - 1].1;
+            kind.matches(text, pos, piece_ends.contains(&pos))
+        };
+
+        let mut chunks = vec![];
+        let mut start = 0;
+        while start < offsets.len() {
+            let max_end = offsets.len().min(start + max_tokens);
+            let end = if max_end == offsets.len() {
+                max_end
+            } else {
+                [boundary, ChunkBoundary::Piece, ChunkBoundary::Token]
+                    .iter()
+                    .find_map(|&kind| (start + 1..=max_end).rev().find(|&end| can_cut(kind, end)))
+                    .unwrap_or_else(|| {
+                        // Not even one character fits, so go over budget rather than split it
+                        (max_end + 1..=offsets.len())
+                            .find(|&end| can_cut(ChunkBoundary::Token, end))
+                            .unwrap()
+                    })
+            };
+            let range = (offsets[start].0, offsets[end - 1].1);
+            chunks.push((&text[range.0..range.1], range));
+            if end == offsets.len() {
+                break;
+            }
+
+            // Always make progress, even if that means less overlap
+            start = end - overlap.min(end - start - 1);
+            while !text.is_char_boundary(offsets[start].0) {
+                start += 1;
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{ChunkBoundary, ChunkError};
+    use crate::tests::setup_core_bpe;
+
+    #[test]
+    fn test_chunk_text() {
+        let bpe = setup_core_bpe();
+        let text = "First sentence here. Second one!\nA new line, with 日本語 in it.\n\nLast.";
+        let (tokens, offsets) = bpe.encode_with_offsets(text, &HashSet::new());
+        for boundary in [
+            ChunkBoundary::Token,
+            ChunkBoundary::Piece,
+            ChunkBoundary::Newline,
+            ChunkBoundary::Sentence,
+        ] {
+            for max_tokens in 1..20 {
+                for overlap in 0..max_tokens.min(4) {
+                    let chunks = bpe.chunk_text(text, max_tokens, overlap, boundary).unwrap();
+                    assert_eq!(chunks.first().unwrap().1 .0, 0);
+                    assert_eq!(chunks.last().unwrap().1 .1, text.len());
+                    for window in chunks.windows(2) {
+                        let (prev, next) = (window[0].1, window[1].1);
+                        assert!(prev.0 < next.0 && next.0 <= prev.1);
+                        if overlap == 0 {
+                            assert_eq!(prev.1, next.0);
+                        }
+                    }
+                    for (chunk, (start, end)) in chunks {
+                        assert_eq!(chunk, &text[start..end]);
+                        let num_tokens = offsets
+                            .iter()
+                            .filter(|o| start <= o.0 && o.1 <= end)
+                            .count();
+                        assert!(num_tokens <= max_tokens || chunk.chars().count() == 1);
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            bpe.chunk_text(text, tokens.len(), 0, ChunkBoundary::Piece),
+            Ok(vec![(text, (0, text.len()))])
+        );
+        assert_eq!(
+            bpe.chunk_text(text, 3, 3, ChunkBoundary::Piece),
+            Err(ChunkError::OverlapTooLarge {
+                overlap: 3,
+                max_tokens: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_text_boundaries() {
+        let bpe = setup_core_bpe();
+        let text = "One two. Three four\nfive six seven eight nine";
+        let line_len = bpe.count_tokens_ordinary("One two. Three four\n");
+        let chunks = bpe
+            .chunk_text(text, line_len + 2, 0, ChunkBoundary::Sentence)
+            .unwrap();
+        assert_eq!(chunks[0].0, "One two. Three four\n");
+        let chunks = bpe
+            .chunk_text(text, line_len - 1, 0, ChunkBoundary::Sentence)
+            .unwrap();
+        assert_eq!(chunks[0].0, "One two.");
+        let chunks = bpe
+            .chunk_text(text, line_len + 2, 0, ChunkBoundary::Newline)
+            .unwrap();
+        assert_eq!(chunks[0].0, "One two. Three four\n");
+        // Falls back to piece boundaries
+        let chunks = bpe
+            .chunk_text(text, line_len - 1, 0, ChunkBoundary::Newline)
+            .unwrap();
+        assert_eq!(chunks[0].0, "One two. Three four");
+
+        assert!(bpe
+            .chunk_text("", 10, 2, ChunkBoundary::Piece)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,697 @@
+//! Conversion between `CoreBPE` and Hugging Face `tokenizer.json` files with a byte-level BPE
+//! model, like the ones of GPT-2 and Llama 3.
+//!
+//! Token values in these files don't have to follow merge priority, so the result encodes with
+//! the file's merge list (see `MergeList`). Anything that would make Hugging Face split or merge
+//! differently, like a normalizer, is an error rather than silently ignored. The post-processor,
+//! truncation and padding only apply to Hugging Face's own `encode`, so they are ignored.
+
+use std::path::Path;
+
+use rustc_hash::FxHashMap as HashMap;
+use serde_json::{json, Value};
+
+use crate::load::{data_gym_byte_order, data_gym_byte_to_char, LoadError};
+use crate::merges::merges_from_ranks;
+use crate::{CoreBPE, Rank};
+
+/// What the `ByteLevel` pre-tokenizer splits on when `use_regex` is set, i.e. the GPT-2 pattern
+const BYTE_LEVEL_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// The parts of a `tokenizer.json` that make up a `CoreBPE`.
+#[derive(Debug)]
+pub struct HfTokenizer {
+    pub pattern: String,
+    pub encoder: HashMap<Vec<u8>, Rank>,
+    /// In priority order
+    pub merges: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The added tokens, which Hugging Face matches before splitting the text
+    pub special_tokens_encoder: HashMap<String, Rank>,
+    /// Whether a piece that is in the vocabulary is encoded as that token without merging
+    pub ignore_merges: bool,
+}
+
+fn unsupported(what: &str) -> LoadError {
+    LoadError::HuggingFace(format!(
+        "tokenizer.json uses {}, which tiktoken can't represent",
+        what
+    ))
+}
+
+fn malformed(what: &str) -> LoadError {
+    LoadError::HuggingFace(format!("tokenizer.json is malformed: {}", what))
+}
+
+fn type_name(value: &Value) -> &str {
+    value["type"].as_str().unwrap_or("untyped")
+}
+
+fn as_rank(value: &Value) -> Result<Rank, LoadError> {
+    value
+        .as_u64()
+        .and_then(|id| Rank::try_from(id).ok())
+        .ok_or_else(|| malformed(&format!("invalid token id {}", value)))
+}
+
+/// Hugging Face splits the text with a sequence of pre-tokenizers, of which we support at most
+/// one `Split` with `Isolated` behavior followed by `ByteLevel`, or `ByteLevel` with its
+/// built-in regex.
+///
+/// The `Split` pattern is an Oniguruma regex, which we take as a `fancy_regex` one. Most of the
+/// syntax is the same, and the rest mostly doesn't compile, which is an error. `Isolated` keeps
+/// the text between two matches as a piece of its own, see `CoreBPE::keep_unmatched`.
+fn pre_tokenizer_pattern(pre_tokenizer: &Value) -> Result<String, LoadError> {
+    let steps: Vec<&Value> = match type_name(pre_tokenizer) {
+        "Sequence" => pre_tokenizer["pretokenizers"]
+            .as_array()
+            .ok_or_else(|| malformed("a Sequence pre-tokenizer without `pretokenizers`"))?
+            .iter()
+            .collect(),
+        _ if pre_tokenizer.is_null() => return Err(unsupported("no pre-tokenizer")),
+        _ => vec![pre_tokenizer],
+    };
+
+    let mut pattern = None;
+    let mut byte_level = false;
+    for step in steps {
+        if byte_level {
+            return Err(unsupported("a pre-tokenizer after ByteLevel"));
+        }
+        match type_name(step) {
+            "Split" => {
+                if pattern.is_some() {
+                    return Err(unsupported("more than one Split pre-tokenizer"));
+                }
+                if step["behavior"] != "Isolated" {
+                    return Err(unsupported(&format!(
+                        "a Split pre-tokenizer with behavior {}",
+                        step["behavior"]
+                    )));
+                }
+                match step["invert"] {
+                    Value::Bool(false) => {}
+                    Value::Bool(true) => {
+                        return Err(unsupported("an inverted Split pre-tokenizer"))
+                    }
+                    _ => return Err(malformed("a Split pre-tokenizer without `invert`")),
+                }
+                let regex = match &step["pattern"] {
+                    Value::Object(p) => match (p.get("Regex"), p.get("String")) {
+                        (Some(Value::String(regex)), None) => regex.clone(),
+                        (None, Some(Value::String(s))) => fancy_regex::escape(s).into_owned(),
+                        _ => {
+                            return Err(malformed("a Split pre-tokenizer with an invalid pattern"))
+                        }
+                    },
+                    _ => return Err(malformed("a Split pre-tokenizer without a pattern")),
+                };
+                if let Err(e) = fancy_regex::Regex::new(&regex) {
+                    return Err(unsupported(&format!(
+                        "the Split pattern {:?}, which doesn't compile ({})",
+                        regex, e
+                    )));
+                }
+                pattern = Some(regex);
+            }
+            "ByteLevel" => {
+                byte_level = true;
+                if step["add_prefix_space"] == true {
+                    return Err(unsupported(
+                        "a ByteLevel pre-tokenizer with add_prefix_space",
+                    ));
+                }
+                // use_regex defaults to true
+                if step["use_regex"] != false {
+                    if pattern.is_some() {
+                        return Err(unsupported(
+                            "a Split pre-tokenizer followed by ByteLevel with use_regex",
+                        ));
+                    }
+                    pattern = Some(BYTE_LEVEL_PATTERN.to_string());
+                }
+            }
+            other => return Err(unsupported(&format!("the {} pre-tokenizer", other))),
+        }
+    }
+    if !byte_level {
+        return Err(unsupported("a pre-tokenizer without ByteLevel"));
+    }
+    // Without any regex, the whole text is a single piece
+    Ok(pattern.unwrap_or_else(|| r"[\s\S]+".to_string()))
+}
+
+pub fn parse_hf_tokenizer_json(contents: &[u8]) -> Result<HfTokenizer, LoadError> {
+    let json: Value = serde_json::from_slice(contents)?;
+
+    let normalizer = &json["normalizer"];
+    let is_empty_sequence = type_name(normalizer) == "Sequence"
+        && normalizer["normalizers"]
+            .as_array()
+            .map_or(false, |n| n.is_empty());
+    if !normalizer.is_null() && !is_empty_sequence {
+        return Err(unsupported(&format!(
+            "the {} normalizer",
+            type_name(normalizer)
+        )));
+    }
+
+    let pattern = pre_tokenizer_pattern(&json["pre_tokenizer"])?;
+
+    let decoder = &json["decoder"];
+    if !decoder.is_null() && type_name(decoder) != "ByteLevel" {
+        return Err(unsupported(&format!("the {} decoder", type_name(decoder))));
+    }
+
+    let model = &json["model"];
+    // Older files don't tag the model, but only BPE models have merges
+    let is_bpe = match model["type"].as_str() {
+        Some(model_type) => model_type == "BPE",
+        None => !model["merges"].is_null(),
+    };
+    if !is_bpe {
+        return Err(unsupported(&format!("the {} model", type_name(model))));
+    }
+    if !model["dropout"].is_null() {
+        return Err(unsupported("BPE dropout"));
+    }
+    for key in ["continuing_subword_prefix", "end_of_word_suffix"] {
+        if !model[key].is_null() && model[key] != "" {
+            return Err(unsupported(&format!("a BPE model with {}", key)));
+        }
+    }
+    if model["byte_fallback"] == true {
+        return Err(unsupported("a BPE model with byte_fallback"));
+    }
+
+    let (_, char_to_byte) = data_gym_byte_order();
+    let decode = |token: &str| -> Result<Vec<u8>, LoadError> {
+        token
+            .chars()
+            .map(|c| {
+                char_to_byte.get(&c).copied().ok_or_else(|| {
+                    unsupported(&format!("the token {:?}, which isn't byte-level", token))
+                })
+            })
+            .collect()
+    };
+
+    let mut encoder: HashMap<Vec<u8>, Rank> = HashMap::default();
+    for (token, id) in model["vocab"]
+        .as_object()
+        .ok_or_else(|| malformed("the model has no `vocab`"))?
+    {
+        encoder.insert(decode(token)?, as_rank(id)?);
+    }
+
+    let merges = model["merges"]
+        .as_array()
+        .ok_or_else(|| malformed("the model has no `merges`"))?
+        .iter()
+        .map(|merge| {
+            // Either "left right" or, in newer files, ["left", "right"]
+            let (left, right) = match merge {
+                Value::String(merge) => merge.split_once(' '),
+                Value::Array(parts) => match parts.as_slice() {
+                    [Value::String(left), Value::String(right)] => Some((&left[..], &right[..])),
+                    _ => None,
+                },
+                _ => None,
+            }
+            .ok_or_else(|| malformed(&format!("invalid merge {}", merge)))?;
+            Ok((decode(left)?, decode(right)?))
+        })
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    let mut special_tokens_encoder: HashMap<String, Rank> = HashMap::default();
+    if let Some(added_tokens) = json["added_tokens"].as_array() {
+        for added in added_tokens {
+            let content = added["content"]
+                .as_str()
+                .ok_or_else(|| malformed("an added token without `content`"))?;
+            for key in ["single_word", "lstrip", "rstrip"] {
+                if added[key] == true {
+                    return Err(unsupported(&format!(
+                        "the added token {:?} with {}",
+                        content, key
+                    )));
+                }
+            }
+            special_tokens_encoder.insert(content.to_string(), as_rank(&added["id"])?);
+        }
+    }
+
+    // Added tokens are often in the vocabulary as well, but can only ever be matched as added
+    // tokens, so they are only kept as special tokens
+    let added_by_id: HashMap<Rank, &str> = special_tokens_encoder
+        .iter()
+        .map(|(content, &id)| (id, content.as_str()))
+        .collect();
+    let mut collision = None;
+    encoder.retain(|token, id| match added_by_id.get(id) {
+        Some(content) if content.as_bytes() == token.as_slice() => false,
+        Some(content) => {
+            collision = Some(format!(
+                "token id {} is both the added token {:?} and {:?} in the vocabulary",
+                id,
+                content,
+                String::from_utf8_lossy(token)
+            ));
+            true
+        }
+        None => true,
+    });
+    if let Some(collision) = collision {
+        return Err(malformed(&collision));
+    }
+
+    Ok(HfTokenizer {
+        pattern,
+        encoder,
+        merges,
+        special_tokens_encoder,
+        ignore_merges: model["ignore_merges"] == true,
+    })
+}
+
+impl HfTokenizer {
+    pub fn into_core_bpe(self) -> Result<CoreBPE, LoadError> {
+        let mut bpe = CoreBPE::from_merges(
+            self.encoder,
+            self.merges,
+            self.special_tokens_encoder,
+            &self.pattern,
+        )?;
+        bpe._set_ignore_merges(self.ignore_merges);
+        bpe._set_keep_unmatched(true);
+        Ok(bpe)
+    }
+}
+
+impl CoreBPE {
+    pub fn from_hf_tokenizer_json(contents: &[u8]) -> Result<Self, LoadError> {
+        parse_hf_tokenizer_json(contents)?.into_core_bpe()
+    }
+
+    pub fn from_hf_tokenizer_json_file<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let contents = std::fs::read(path)?;
+        Self::from_hf_tokenizer_json(&contents)
+    }
+
+    /// Builds a `tokenizer.json` that Hugging Face tokenizers encode the same way as `encode`
+    /// with all special tokens allowed, since Hugging Face always matches added tokens.
+    ///
+    /// Without an explicit merge list, each token gets the merge that `derive_merges` finds for
+    /// it, in rank order, and `ignore_merges` is set so that whole pieces are looked up first
+    /// like `encode` does. That needs a version of tokenizers that knows `ignore_merges`.
+    pub fn to_hf_tokenizer_json(&self) -> String {
+        let byte_to_char = data_gym_byte_to_char();
+        let byte_level =
+            |token: &[u8]| -> String { token.iter().map(|&b| byte_to_char[b as usize]).collect() };
+
+        let (merges, ignore_merges) = match &self.merge_list {
+            Some(merge_list) => (
+                merge_list
+                    .merges()
+                    .iter()
+                    .map(|(left, right)| (self.decoder[left].clone(), self.decoder[right].clone()))
+                    .collect(),
+                merge_list.ignore_merges,
+            ),
+            None => (merges_from_ranks(&self.encoder), true),
+        };
+
+        let vocab: serde_json::Map<String, Value> = self
+            .encoder
+            .iter()
+            .map(|(token, &rank)| (byte_level(token), json!(rank)))
+            .collect();
+        let mut special_tokens: Vec<(&String, &Rank)> =
+            self.special_tokens_encoder.iter().collect();
+        special_tokens.sort_by_key(|&(_, &rank)| rank);
+        let added_tokens: Vec<Value> = special_tokens
+            .into_iter()
+            .map(|(content, &rank)| {
+                json!({
+                    "id": rank,
+                    "content": content,
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": true,
+                })
+            })
+            .collect();
+
+        let tokenizer_json = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": null,
+            "pre_tokenizer": {
+                "type": "Sequence",
+                "pretokenizers": [
+                    {
+                        "type": "Split",
+                        "pattern": {"Regex": self._get_regex().as_str()},
+                        "behavior": "Isolated",
+                        "invert": false,
+                    },
+                    {
+                        "type": "ByteLevel",
+                        "add_prefix_space": false,
+                        "trim_offsets": true,
+                        "use_regex": false,
+                    },
+                ],
+            },
+            "post_processor": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": false,
+                "use_regex": false,
+            },
+            "decoder": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": false,
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "ignore_merges": ignore_merges,
+                "vocab": vocab,
+                "merges": merges
+                    .iter()
+                    .map(|(left, right)| format!("{} {}", byte_level(left), byte_level(right)))
+                    .collect::<Vec<_>>(),
+            },
+        });
+        serde_json::to_string_pretty(&tokenizer_json).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::{json, Value};
+
+    use super::parse_hf_tokenizer_json;
+    use crate::load::{data_gym_byte_to_char, LoadError};
+    use crate::tests::{setup_core_bpe, setup_encoder, Lcg};
+    use crate::{CoreBPE, Rank};
+
+    fn byte_level(token: &[u8]) -> String {
+        let byte_to_char = data_gym_byte_to_char();
+        token.iter().map(|&b| byte_to_char[b as usize]).collect()
+    }
+
+    /// A GPT-2 style tokenizer.json, where token ids are the reverse of merge priority
+    fn tokenizer_json() -> Value {
+        let merges: &[(&[u8], &[u8])] = &[
+            (b" ", b"w"),
+            (b"o", b"r"),
+            (b"l", b"d"),
+            (b" w", b"or"),
+            (b" wor", b"ld"),
+            (b"h", b"e"),
+        ];
+        let mut vocab: serde_json::Map<String, Value> =
+            (0..=255u8).map(|b| (byte_level(&[b]), json!(b))).collect();
+        for (i, (left, right)) in merges.iter().enumerate() {
+            let merged = [*left, *right].concat();
+            vocab.insert(byte_level(&merged), json!(256 + merges.len() - i));
+        }
+        vocab.insert("<|endoftext|>".to_string(), json!(300));
+        json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": 300,
+                "content": "<|endoftext|>",
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": true,
+                "special": true,
+            }],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+            "post_processor": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": false, "use_regex": true},
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": "",
+                "end_of_word_suffix": "",
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": merges
+                    .iter()
+                    .map(|(left, right)| format!("{} {}", byte_level(left), byte_level(right)))
+                    .collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    fn load(json: &Value) -> Result<CoreBPE, LoadError> {
+        CoreBPE::from_hf_tokenizer_json(json.to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json() {
+        let json = tokenizer_json();
+        let bpe = load(&json).unwrap();
+        let vocab = &json["model"]["vocab"];
+        let id = |token: &[u8]| vocab[byte_level(token)].as_u64().unwrap() as u32;
+        assert_eq!(
+            bpe.encode(
+                "hello world<|endoftext|>",
+                &HashSet::from(["<|endoftext|>"])
+            ),
+            vec![id(b"he"), id(b"l"), id(b"l"), id(b"o"), id(b" world"), 300]
+        );
+        assert_eq!(
+            bpe.decode_bytes(&[id(b" world"), 300]).unwrap(),
+            b" world<|endoftext|>"
+        );
+        // <|endoftext|> is only a special token, not an ordinary one
+        assert_eq!(bpe.encode_single_token(b"<|endoftext|>"), Some(300));
+        assert_eq!(bpe.encode_ordinary("<|endoftext|>").len(), 13);
+
+        // Merges as pairs, like newer files have them
+        let mut json = tokenizer_json();
+        json["model"]["merges"] = json["model"]["merges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|merge| json!(merge.as_str().unwrap().split(' ').collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(
+            load(&json).unwrap().encode_ordinary(" world"),
+            vec![id(b" world")]
+        );
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json_split() {
+        // Llama 3 style: a Split with the regex, then ByteLevel without one
+        let mut json = tokenizer_json();
+        json["pre_tokenizer"] = json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                {"type": "Split", "pattern": {"Regex": r"\p{L}+|\s+|[^\s\p{L}]+"}, "behavior": "Isolated", "invert": false},
+                {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false},
+            ],
+        });
+        let parsed = parse_hf_tokenizer_json(json.to_string().as_bytes()).unwrap();
+        assert_eq!(parsed.pattern, r"\p{L}+|\s+|[^\s\p{L}]+");
+        assert!(!parsed.ignore_merges);
+        // " world" is now two pieces, " " and "w" + "or" + "ld"
+        assert_eq!(load(&json).unwrap().encode_ordinary(" world").len(), 4);
+
+        // With ignore_merges, a piece in the vocabulary is a token even if merges wouldn't get there
+        json["model"]["ignore_merges"] = json!(true);
+        json["model"]["vocab"]["hello"] = json!(400);
+        let bpe = load(&json).unwrap();
+        assert_eq!(bpe.encode_ordinary("hello"), vec![400]);
+        json["model"]["ignore_merges"] = json!(false);
+        assert_eq!(load(&json).unwrap().encode_ordinary("hello").len(), 4);
+
+        // Text the pattern doesn't match is kept, as a piece of its own
+        json["pre_tokenizer"]["pretokenizers"][0]["pattern"] = json!({"Regex": r"\p{L}+"});
+        let bpe = load(&json).unwrap();
+        let text = "hello,  world!";
+        let tokens = bpe.encode_ordinary(text);
+        assert_eq!(bpe.decode_bytes(&tokens).unwrap(), text.as_bytes());
+        assert_eq!(
+            tokens,
+            ["hello", ",  ", "world", "!"]
+                .iter()
+                .flat_map(|piece| bpe.encode_single_piece(piece.as_bytes()))
+                .collect::<Vec<_>>()
+        );
+
+        // The pattern is used as is, so backreferences still work
+        json["pre_tokenizer"]["pretokenizers"][0]["pattern"] = json!({"Regex": r"(\w)\1"});
+        let bpe = load(&json).unwrap();
+        // "oo", "r ", "ll", "d", so neither "or" nor "ld" merges
+        assert_eq!(bpe.encode_ordinary("oor lld").len(), 7);
+    }
+
+    #[test]
+    fn test_to_hf_tokenizer_json() {
+        let allowed_special = HashSet::from(["<|endoftext|>", "<|fim_prefix|>"]);
+        let texts = [
+            "hello world, the world is hello",
+            "héllo wörld <|endoftext|>  indented\n\n\tand <|fim_prefix|>you're 12345!",
+            "hellohello worldworld",
+        ];
+
+        // With ranks, exported merges have to encode exactly like the ranks do
+        let bpe = setup_core_bpe();
+        let exported = bpe.to_hf_tokenizer_json();
+        let json: Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(json["model"]["ignore_merges"], true);
+        assert_eq!(json["added_tokens"][0]["content"], "<|endoftext|>");
+        let imported = CoreBPE::from_hf_tokenizer_json(exported.as_bytes()).unwrap();
+        for text in texts {
+            assert_eq!(
+                imported.encode(text, &allowed_special),
+                bpe.encode(text, &allowed_special)
+            );
+        }
+
+        // With merges, exporting round trips
+        let bpe = load(&tokenizer_json()).unwrap();
+        let exported = bpe.to_hf_tokenizer_json();
+        let json: Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(json["model"]["merges"], tokenizer_json()["model"]["merges"]);
+        assert_eq!(json["model"]["ignore_merges"], false);
+        let imported = CoreBPE::from_hf_tokenizer_json(exported.as_bytes()).unwrap();
+        for text in texts {
+            assert_eq!(
+                imported.encode(text, &allowed_special),
+                bpe.encode(text, &allowed_special)
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_hf_tokenizer_json_random_ranks() {
+        // Over a two letter alphabet, most tokens can be formed from two others in several ways,
+        // like "aba" from "ab" + "a" and "a" + "ba"
+        let mut rng = Lcg(17);
+        for _ in 0..50 {
+            let mut encoder = setup_encoder(&[]);
+            for _ in 0..(rng.next() % 300) {
+                let token: Vec<u8> = (0..2 + rng.next() % 7)
+                    .map(|_| [b'a', b'b'][rng.next() % 2])
+                    .collect();
+                let reachable = (1..token.len()).any(|i| {
+                    encoder.contains_key(&token[..i]) && encoder.contains_key(&token[i..])
+                });
+                if reachable && !encoder.contains_key(&token) {
+                    let rank = encoder.len() as Rank;
+                    encoder.insert(token, rank);
+                }
+            }
+
+            let bpe = CoreBPE::new(encoder, [], r"\S+|\s+").unwrap();
+            let imported =
+                CoreBPE::from_hf_tokenizer_json(bpe.to_hf_tokenizer_json().as_bytes()).unwrap();
+            for _ in 0..50 {
+                let text: String = (0..rng.next() % 40)
+                    .map(|_| ['a', 'b', ' '][rng.next() % 3])
+                    .collect();
+                assert_eq!(
+                    imported.encode_ordinary(&text),
+                    bpe.encode_ordinary(&text),
+                    "{:?}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json_unsupported() {
+        let cases: &[(&str, Value)] = &[
+            ("/normalizer", json!({"type": "NFC"})),
+            (
+                "/pre_tokenizer",
+                json!({"type": "Metaspace", "replacement": "▁"}),
+            ),
+            ("/pre_tokenizer/add_prefix_space", json!(true)),
+            ("/decoder", json!({"type": "WordPiece"})),
+            ("/model/type", json!("WordPiece")),
+            ("/model/dropout", json!(0.1)),
+            ("/model/byte_fallback", json!(true)),
+            ("/model/continuing_subword_prefix", json!("##")),
+            ("/added_tokens/0/lstrip", json!(true)),
+        ];
+        for (pointer, value) in cases {
+            let mut json = tokenizer_json();
+            *json.pointer_mut(pointer).unwrap() = value.clone();
+            match load(&json) {
+                Err(LoadError::HuggingFace(e)) => assert!(e.contains("can't represent"), "{}", e),
+                other => panic!("{}: expected an error, got {:?}", pointer, other.err()),
+            }
+        }
+
+        let split = |split: Value| {
+            let mut json = tokenizer_json();
+            json["pre_tokenizer"] = json!({
+                "type": "Sequence",
+                "pretokenizers": [split, {"type": "ByteLevel", "use_regex": false}],
+            });
+            load(&json)
+        };
+        for (behavior, invert, pattern) in [
+            ("Removed", json!(false), r"\s+"),
+            ("MergedWithPrevious", json!(false), r"\s+"),
+            ("Isolated", json!(true), r"\s+"),
+            // Oniguruma's generic newline
+            ("Isolated", json!(false), r"\R"),
+        ] {
+            let result = split(json!({
+                "type": "Split",
+                "pattern": {"Regex": pattern},
+                "behavior": behavior,
+                "invert": invert,
+            }));
+            match result {
+                Err(LoadError::HuggingFace(e)) => assert!(e.contains("can't represent"), "{}", e),
+                other => panic!(
+                    "{} {}: expected an error, got {:?}",
+                    behavior,
+                    invert,
+                    other.err()
+                ),
+            }
+        }
+        assert!(matches!(
+            split(json!({"type": "Split", "pattern": {"Regex": r"\s+"}, "behavior": "Isolated"})),
+            Err(LoadError::HuggingFace(_))
+        ));
+
+        let mut json = tokenizer_json();
+        json["added_tokens"][0]["id"] = json!(262);
+        assert!(matches!(load(&json), Err(LoadError::HuggingFace(_))));
+        let mut json = tokenizer_json();
+        json["model"]["merges"] = json!(["Ġ x"]);
+        assert!(matches!(load(&json), Err(LoadError::Build(_))));
+    }
+}
@@ -1,28 +1,54 @@
 // This check is new and seems buggy (possibly with PyO3 interaction)
 #![allow(clippy::borrow_deref_ref)]
 
-use std::collections::HashSet;
-use std::num::NonZeroU64;
-use std::thread;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+use std::sync::Arc;
 
+use aho_corasick::{AhoCorasick, Input, MatchKind};
 use fancy_regex::Regex;
-use pyo3::exceptions;
-use pyo3::prelude::*;
-use pyo3::pyclass;
-use pyo3::PyResult;
-use pyo3::types::{PyBytes, PyList, PyTuple};
+use regex_automata::util::pool::{Pool, PoolGuard};
 use rustc_hash::FxHashMap as HashMap;
 
-type Rank = u32;
+pub mod batch;
+pub mod chunk;
+pub mod huggingface;
+pub mod linear;
+pub mod load;
+pub mod merges;
+#[cfg(feature = "python")]
+mod py;
+pub mod stream;
+pub mod train;
+pub mod validate;
+
+use linear::LinearEncoder;
+use merges::MergeList;
+use validate::{_validate_vocab, special_token_collisions, VocabError};
+
+pub type Rank = u32;
+
+/// Pieces at least this long are merged with `_byte_pair_merge_large`.
+const LARGE_PIECE_THRESHOLD: usize = 128;
 
 fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    if piece.len() < LARGE_PIECE_THRESHOLD {
+        _byte_pair_merge_small(ranks, piece)
+    } else {
+        _byte_pair_merge_large(ranks, piece)
+    }
+}
+
+fn _byte_pair_merge_small(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
     // This is a vector of (start, rank).
     // The rank is of the pair starting at position start.
     let mut parts = Vec::with_capacity(piece.len() + 1);
 
     // Note that we hash bytes when indexing into `ranks`, not token pairs. As long as we train BPE
     // the way we currently do, this is equivalent. An easy way to break this would be to decouple
-    // merge priority from token index or to prevent specific token merges.
+    // merge priority from token index or to prevent specific token merges. `MergeList` handles
+    // vocabularies like that.
     let mut min_rank: (Rank, usize) = (Rank::MAX, usize::MAX);
     for i in 0..piece.len() - 1 {
         let rank = *ranks.get(&piece[i..i + 2]).unwrap_or(&Rank::MAX);
@@ -50,9 +76,9 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     };
 
     // If you have n parts and m merges, this does O(mn) work.
-    // We could do something with a heap and do O(m log n) work.
     // n is often very small so considerations like cache-locality outweigh the algorithmic
-    // complexity downsides of the `parts` vector.
+    // complexity downsides of the `parts` vector. Long pieces (think base64 blobs or runs of
+    // whitespace) go through the heap based `_byte_pair_merge_large` instead.
     while min_rank.0 != Rank::MAX {
         let i = min_rank.1;
         // Update parts[i] and parts[i - 1] before removing parts[i + 1], since
@@ -73,9 +99,71 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     parts
 }
 
+/// Same result as `_byte_pair_merge_small`, but does O(m log n) work using a heap over a linked
+/// list of parts. Entries in the heap are invalidated lazily: an entry is stale if its part has
+/// been merged away or if the rank of its pair has changed since it was pushed.
+fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    // Parts are identified by their start position, with `piece.len()` acting as a sentinel for
+    // the end. `next` and `prev` link the parts that are still alive.
+    let n = piece.len();
+    let mut next: Vec<usize> = (1..=n + 1).collect();
+    let mut prev: Vec<usize> = (0..=n).map(|i| i.wrapping_sub(1)).collect();
+    let mut alive = vec![true; n + 1];
+    let mut pair_ranks = vec![Rank::MAX; n + 1];
+
+    let get_rank = |next: &[usize], start: usize| -> Rank {
+        let mid = next[start];
+        if mid >= n {
+            return Rank::MAX;
+        }
+        *ranks.get(&piece[start..next[mid]]).unwrap_or(&Rank::MAX)
+    };
+
+    // Ties are broken by start position, which matches the leftmost-first scan of
+    // `_byte_pair_merge_small`.
+    let mut heap = BinaryHeap::with_capacity(n);
+    for (start, pair_rank) in pair_ranks.iter_mut().enumerate().take(n - 1) {
+        *pair_rank = get_rank(&next, start);
+        if *pair_rank != Rank::MAX {
+            heap.push(Reverse((*pair_rank, start)));
+        }
+    }
+
+    while let Some(Reverse((rank, start))) = heap.pop() {
+        if !alive[start] || pair_ranks[start] != rank {
+            continue;
+        }
+        // Merge the part at `start` with the part after it
+        let removed = next[start];
+        alive[removed] = false;
+        next[start] = next[removed];
+        prev[next[removed]] = start;
+
+        pair_ranks[start] = get_rank(&next, start);
+        if pair_ranks[start] != Rank::MAX {
+            heap.push(Reverse((pair_ranks[start], start)));
+        }
+        if start > 0 {
+            let before = prev[start];
+            pair_ranks[before] = get_rank(&next, before);
+            if pair_ranks[before] != Rank::MAX {
+                heap.push(Reverse((pair_ranks[before], before)));
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while start <= n {
+        parts.push((start, Rank::MAX));
+        start = next[start];
+    }
+    parts
+}
+
 pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Rank> {
     assert!(piece.len() > 1);
-    _byte_pair_merge(&ranks, &piece)
+    _byte_pair_merge(ranks, piece)
         .windows(2)
         .map(|part| ranks[&piece[part[0].0..part[1].0]])
         .collect()
@@ -83,12 +171,36 @@ pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Ran
 
 pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<&'a [u8]> {
     assert!(piece.len() > 1);
-    _byte_pair_merge(&ranks, &piece)
+    _byte_pair_merge(ranks, piece)
         .windows(2)
         .map(|part| &piece[part[0].0..part[1].0])
         .collect()
 }
 
+/// Converts byte spans in `text` to spans of chars (i.e. Python string indices). If a token starts
+/// or ends in the middle of a character, its span includes that whole character.
+pub fn byte_offsets_to_char_offsets(text: &str, offsets: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // char_index[i] is the index of the char that byte i is part of
+    let mut char_index = Vec::with_capacity(text.len() + 1);
+    let mut num_chars = 0;
+    for c in text.chars() {
+        char_index.extend(std::iter::repeat(num_chars).take(c.len_utf8()));
+        num_chars += 1;
+    }
+    char_index.push(num_chars);
+    offsets
+        .iter()
+        .map(|&(start, end)| {
+            let char_end = if text.is_char_boundary(end) {
+                char_index[end]
+            } else {
+                char_index[end] + 1
+            };
+            (char_index[start], char_end)
+        })
+        .collect()
+}
+
 // Various performance notes:
 //
 // Regex
@@ -105,13 +217,9 @@ pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> V
 // some mutable scratch space inside of `regex`. This absolutely kills performance. When using plain
 // old `regex`, we don't hit this, because `find_iter` has a different code path.
 // Related: https://github.com/rust-lang/regex/blob/master/PERFORMANCE.md
-// Anyway, the way we get around this is with having a (mostly) thread local clone of the regex for
-// each thread.
-//
-// Threading
-// =========
-// I tried using `rayon`. It wasn't really faster than using Python threads and releasing the GIL.
-// So goodbye `rayon`! Let thread count etc be in control of our Python users.
+// Anyway, the way we get around this is with a pool of clones of the regex, see `RegexPool`. The
+// first thread to use it gets its own clone without any locking, and other threads take a clone
+// from a few sharded stacks, making a new one rather than waiting if a stack is busy.
 //
 // Caching
 // =======
@@ -130,46 +238,265 @@ pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> V
 // The current implementation ends up doing a lot of hashing of bytes. In theory, this could be made
 // to be hashing of two-tuples of ints, which looks like it may also be a couple percent faster.
 
-pub struct FakeThreadId(NonZeroU64);
-
-fn hash_current_thread() -> usize {
-    // It's easier to use unsafe than to use nightly. Rust has this nice u64 thread id counter
-    // that works great for our use case of avoiding collisions in our array. Unfortunately,
-    // it's private. However, there are only so many ways you can layout a u64, so just transmute
-    // https://github.com/rust-lang/rust/issues/67939
-    const _: [u8; 8] = [0; std::mem::size_of::<thread::ThreadId>()];
-    const _: [u8; 8] = [0; std::mem::size_of::<FakeThreadId>()];
-    let x = unsafe {
-        std::mem::transmute::<thread::ThreadId, FakeThreadId>(thread::current().id()).0
-    };
-    u64::from(x) as usize
+/// Makes a fresh clone of a regex for `RegexPool`. Clones share what `regex` compiled, so they
+/// mostly cost scratch space.
+type RegexCreate = Box<dyn Fn() -> Regex + Send + Sync>;
+
+/// Clones of a regex, so that threads don't fight over its scratch space. See the performance
+/// notes above.
+type RegexPool = Pool<Regex, RegexCreate>;
+
+fn regex_pool(regex: Regex) -> RegexPool {
+    Pool::new(Box::new(move || regex.clone()))
+}
+
+/// The pieces that a regex splits a text into, as byte ranges. With `keep_unmatched`, the text
+/// between two matches is a piece of its own rather than dropped, see `CoreBPE::keep_unmatched`.
+struct Pieces<'r, 't> {
+    matches: fancy_regex::Matches<'r, 't>,
+    keep_unmatched: bool,
+    text_len: usize,
+    /// Where the last piece ended
+    pos: usize,
+    /// A match that comes after the unmatched piece that was just returned
+    next_match: Option<std::ops::Range<usize>>,
 }
 
-const MAX_NUM_THREADS: usize = 128;
+impl Iterator for Pieces<'_, '_> {
+    type Item = std::ops::Range<usize>;
 
-#[pyclass]
-struct CoreBPE {
-    encoder: HashMap<Vec<u8>, Rank>,
+    fn next(&mut self) -> Option<Self::Item> {
+        let next_match = (self.next_match.take())
+            .or_else(|| self.matches.next().map(|mat| mat.unwrap().range()));
+        if self.keep_unmatched {
+            let unmatched_end = next_match
+                .as_ref()
+                .map_or(self.text_len, |range| range.start);
+            if self.pos < unmatched_end {
+                let unmatched = self.pos..unmatched_end;
+                self.pos = unmatched_end;
+                self.next_match = next_match;
+                return Some(unmatched);
+            }
+        }
+        let range = next_match?;
+        self.pos = range.end;
+        Some(range)
+    }
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    InvalidPattern(fancy_regex::Error),
+    /// The vocabulary can't be used with the requested `Backend`.
+    UnsupportedVocab(String),
+    /// Everything `validate_vocab` found wrong with the vocabulary, which is never empty.
+    InvalidVocab(Vec<VocabError>),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidPattern(e) => write!(f, "{}", e),
+            BuildError::UnsupportedVocab(reason) => write!(f, "Unsupported vocabulary: {}", reason),
+            BuildError::InvalidVocab(errors) => {
+                write!(f, "Invalid vocabulary: {}", errors[0])?;
+                for e in errors.iter().skip(1).take(4) {
+                    write!(f, "; {}", e)?;
+                }
+                if errors.len() > 5 {
+                    write!(f, "; and {} more problems", errors.len() - 5)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<fancy_regex::Error> for BuildError {
+    fn from(e: fancy_regex::Error) -> Self {
+        BuildError::InvalidPattern(e)
+    }
+}
+
+/// A token that is neither an ordinary nor a special token, which usually means the tokens came
+/// from a model with a bigger vocabulary or were corrupted on the way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub token: Rank,
+    /// The index of the token in the list of tokens.
+    pub position: usize,
+    /// Which list of tokens it was in, when decoding a batch.
+    pub batch_index: Option<usize>,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid token {} at position {}",
+            self.token, self.position
+        )?;
+        if let Some(batch_index) = self.batch_index {
+            write!(f, " of batch item {}", batch_index)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Text in the input that is a disallowed special token, see `encode_checked`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisallowedSpecialError {
+    pub token: String,
+    /// Where the token starts in the text, in bytes.
+    pub offset: usize,
+}
+
+impl fmt::Display for DisallowedSpecialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Encountered text corresponding to disallowed special token {:?} at byte {}",
+            self.token, self.offset
+        )
+    }
+}
+
+impl std::error::Error for DisallowedSpecialError {}
+
+/// What `encode_with_policy` does with text that matches a special token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecialTokenPolicy {
+    /// Allowed special tokens are encoded as special tokens, other special tokens as text.
+    Allow,
+    /// Allowed special tokens are encoded as special tokens, other special tokens are an error.
+    #[default]
+    Raise,
+    /// All special tokens are encoded as text, whatever is allowed. Use this for untrusted text,
+    /// since it never even looks for special tokens.
+    TreatAsText,
+}
+
+/// A set of special tokens for `encode_checked`.
+#[derive(Clone, Copy, Debug)]
+pub enum SpecialTokenSet<'a> {
+    /// Every special token. As the disallowed set, every special token that isn't allowed.
+    All,
+    Only(&'a HashSet<&'a str>),
+}
+
+impl SpecialTokenSet<'_> {
+    fn contains(&self, token: &str) -> bool {
+        match self {
+            SpecialTokenSet::All => true,
+            SpecialTokenSet::Only(tokens) => tokens.contains(token),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            SpecialTokenSet::All => false,
+            SpecialTokenSet::Only(tokens) => tokens.is_empty(),
+        }
+    }
+}
+
+/// What decoding does with tokens that aren't in the vocabulary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownTokenPolicy {
+    /// Return a `DecodeError`.
+    #[default]
+    Raise,
+    /// Leave them out.
+    Skip,
+    /// Decode them to U+FFFD, the replacement character.
+    Replace,
+}
+
+/// The algorithm used to encode pieces that aren't a single token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// `byte_pair_encode`. Fast in practice, but quadratic in the worst case.
+    #[default]
+    Merge,
+    /// `LinearEncoder`. Slower to construct, but linear time in the worst case, so this is what
+    /// you want when encoding untrusted input.
+    Linear,
+}
+
+#[cfg_attr(feature = "python", pyo3::pyclass)]
+pub struct CoreBPE {
+    // The ordinary tokens are behind `Arc`s, so that `with_special_tokens` can share them
+    encoder: Arc<HashMap<Vec<u8>, Rank>>,
     special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
+    decoder: Arc<HashMap<Rank, Vec<u8>>>,
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
-    regex_tls: Vec<Regex>,
-    special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    regex_pool: Arc<RegexPool>,
+    special_matcher: AhoCorasick,
+    sorted_token_bytes: Arc<Vec<Vec<u8>>>,
+    linear_encoder: Option<Arc<LinearEncoder>>,
+    merge_list: Option<Arc<MergeList>>,
+    /// Whether text that the regex doesn't match is a piece of its own, like with a Hugging Face
+    /// `Split` pre-tokenizer with `Isolated` behavior, instead of being dropped
+    keep_unmatched: bool,
 }
 
 impl CoreBPE {
-    fn _get_tl_regex(&self) -> &Regex {
-        // See performance notes above for what this is about
-        // It's also a little janky, please make a better version of it!
-        // However, it's nice that this doesn't leak memory to short-lived threads
-        &self.regex_tls[hash_current_thread() % MAX_NUM_THREADS]
+    fn _get_regex(&self) -> PoolGuard<'_, Regex, RegexCreate> {
+        // See performance notes above for what this is about. The pool only grows to the number
+        // of threads encoding at the same time, and clones go back to it when the guard drops,
+        // so this doesn't leak memory to short-lived threads either.
+        self.regex_pool.get()
     }
 
-    fn _get_tl_special_regex(&self) -> &Regex {
-        &self.special_regex_tls[hash_current_thread() % MAX_NUM_THREADS]
+    fn _pieces<'r, 't>(&self, regex: &'r Regex, text: &'t str) -> Pieces<'r, 't> {
+        Pieces {
+            matches: regex.find_iter(text),
+            keep_unmatched: self.keep_unmatched,
+            text_len: text.len(),
+            pos: 0,
+            next_match: None,
+        }
+    }
+
+    fn _byte_pair_encode(&self, piece: &[u8]) -> Vec<Rank> {
+        if let Some(merge_list) = &self.merge_list {
+            return merge_list.encode(piece);
+        }
+        match &self.linear_encoder {
+            Some(linear_encoder) => linear_encoder.encode(piece),
+            None => byte_pair_encode(piece, &self.encoder),
+        }
+    }
+
+    /// The token for a whole piece, which is a shortcut for `_byte_pair_encode`. With an explicit
+    /// merge list, a piece can be in the vocabulary and still be encoded as something else.
+    fn _piece_token(&self, piece: &[u8]) -> Option<Rank> {
+        if matches!(&self.merge_list, Some(merge_list) if !merge_list.ignore_merges) {
+            return None;
+        }
+        self.encoder.get(piece).copied()
+    }
+
+    /// Same as `self._byte_pair_encode(piece).len()`, without building the tokens
+    fn _count_piece(&self, piece: &[u8]) -> usize {
+        if self._piece_token(piece).is_some() {
+            return 1;
+        }
+        if let Some(merge_list) = &self.merge_list {
+            return merge_list.count(piece);
+        }
+        match &self.linear_encoder {
+            Some(linear_encoder) => linear_encoder.count(piece),
+            None => _byte_pair_merge(&self.encoder, piece).len() - 1,
+        }
     }
 
+    /// Only for tokens we produced ourselves, use `_decode_checked` for anything from outside.
     fn _decode_native(&self, tokens: &[Rank]) -> Vec<u8> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
         for token in tokens {
@@ -182,55 +509,166 @@ impl CoreBPE {
         ret
     }
 
+    fn _decode_checked(
+        &self,
+        tokens: &[Rank],
+        unknown: UnknownTokenPolicy,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for (position, &token) in tokens.iter().enumerate() {
+            match self.decode_single_token_bytes(token) {
+                Some(token_bytes) => ret.extend(token_bytes),
+                None => match unknown {
+                    UnknownTokenPolicy::Raise => {
+                        return Err(DecodeError {
+                            token,
+                            position,
+                            batch_index: None,
+                        })
+                    }
+                    UnknownTokenPolicy::Skip => {}
+                    UnknownTokenPolicy::Replace => ret.extend(
+                        char::REPLACEMENT_CHARACTER
+                            .encode_utf8(&mut [0; 4])
+                            .as_bytes(),
+                    ),
+                },
+            }
+        }
+        Ok(ret)
+    }
+
     fn _encode_ordinary_native(&self, text: &str) -> Vec<Rank> {
         // This is the core of the encoding logic; the other functions in here
         // just make things complicated :-)
-        let regex = self._get_tl_regex();
+        let regex = self._get_regex();
         let mut ret = vec![];
-        for mat in regex.find_iter(text) {
-            let piece = mat.unwrap().as_str().as_bytes();
-            match self.encoder.get(piece) {
-                Some(token) => ret.push(*token),
-                None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
+        for range in self._pieces(&regex, text) {
+            let piece = text[range].as_bytes();
+            match self._piece_token(piece) {
+                Some(token) => ret.push(token),
+                None => ret.extend(&self._byte_pair_encode(piece)),
             }
         }
         ret
     }
 
+    /// Finds the next allowed special token at or after `start`, if any. When allowed special
+    /// tokens overlap, the one that starts first wins, and then the longest one.
+    fn _find_allowed_special(
+        &self,
+        text: &str,
+        start: usize,
+        allowed_special: &HashSet<&str>,
+    ) -> Option<aho_corasick::Match> {
+        self._find_special(
+            text,
+            start,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .map(|(m, _)| m)
+    }
+
+    /// Like `_find_allowed_special`, but disallowed special tokens compete for the match too.
+    /// Returns whether the match is allowed.
+    fn _find_special(
+        &self,
+        text: &str,
+        start: usize,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Option<(aho_corasick::Match, bool)> {
+        if allowed_special.is_empty() && disallowed_special.is_empty() {
+            return None;
+        }
+        let max_len = self.special_matcher.max_pattern_len();
+        let mut best: Option<(aho_corasick::Match, bool)> = None;
+        // Overlapping matches come out in order of where they end, so once they end more than
+        // `max_len` past the start of the best match, nothing can beat it any more
+        for m in self
+            .special_matcher
+            .find_overlapping_iter(Input::new(text).span(start..text.len()))
+        {
+            if let Some((b, _)) = best {
+                if m.end() > b.start() + max_len {
+                    break;
+                }
+            }
+            let token = &text[m.range()];
+            let disallowed = match disallowed_special {
+                SpecialTokenSet::All => !allowed_special.contains(token),
+                SpecialTokenSet::Only(tokens) => tokens.contains(token),
+            };
+            if !disallowed && !allowed_special.contains(token) {
+                continue;
+            }
+            best = match best {
+                Some((b, _))
+                    if b.start() < m.start() || b.start() == m.start() && b.end() >= m.end() =>
+                {
+                    best
+                }
+                _ => Some((m, !disallowed)),
+            };
+        }
+        best
+    }
+
+    /// Like `_find_special`, but a disallowed match is an error.
+    fn _next_special(
+        &self,
+        text: &str,
+        start: usize,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<Option<aho_corasick::Match>, DisallowedSpecialError> {
+        match self._find_special(text, start, allowed_special, disallowed_special) {
+            Some((m, false)) => Err(DisallowedSpecialError {
+                token: text[m.range()].to_string(),
+                offset: m.start(),
+            }),
+            next_special => Ok(next_special.map(|(m, _)| m)),
+        }
+    }
+
     fn _encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
-        let special_regex = self._get_tl_special_regex();
-        let regex = self._get_tl_regex();
+        match self._encode_native_checked(
+            text,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        ) {
+            Ok(ret) => ret,
+            Err(_) => unreachable!("nothing is disallowed"),
+        }
+    }
+
+    fn _encode_native_checked(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, usize), DisallowedSpecialError> {
+        let regex = self._get_regex();
         let mut ret = vec![];
 
         let mut start = 0;
         let mut last_piece_token_len = 0;
         loop {
-            let mut next_special;
-            let mut start_find = start;
-            loop {
-                // Find the next allowed special token, if any
-                next_special = special_regex.find_from_pos(text, start_find).unwrap();
-                match next_special {
-                    Some(m) => {
-                        if allowed_special.contains(&text[m.start()..m.end()]) {
-                            break;
-                        }
-                        start_find = m.start() + 1;
-                    }
-                    None => break,
-                }
-            }
+            let next_special =
+                self._next_special(text, start, allowed_special, disallowed_special)?;
             let end = next_special.map_or(text.len(), |m| m.start());
 
             // Okay, here we go, compare this logic to _encode_ordinary_native
-            for mat in regex.find_iter(&text[start..end]) {
-                let piece = mat.unwrap().as_str().as_bytes();
-                if let Some(token) = self.encoder.get(piece) {
+            let segment = &text[start..end];
+            for range in self._pieces(&regex, segment) {
+                let piece = segment[range].as_bytes();
+                if let Some(token) = self._piece_token(piece) {
                     last_piece_token_len = 1;
-                    ret.push(*token);
+                    ret.push(token);
                     continue;
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
+                let tokens = self._byte_pair_encode(piece);
                 last_piece_token_len = tokens.len();
                 ret.extend(&tokens);
             }
@@ -238,7 +676,7 @@ impl CoreBPE {
             match next_special {
                 // And here we push the special token
                 Some(m) => {
-                    let piece = m.as_str();
+                    let piece = &text[m.range()];
                     let token = self.special_tokens_encoder[piece];
                     ret.push(token);
                     start = m.end();
@@ -250,7 +688,112 @@ impl CoreBPE {
 
         // last_piece_token_len is how many tokens came from the last regex split. This is used
         // for determining unstable tokens, since you can't merge across (stable) regex splits
-        (ret, last_piece_token_len)
+        Ok((ret, last_piece_token_len))
+    }
+
+    fn _count_native(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<usize, DisallowedSpecialError> {
+        // Compare this logic to _encode_native_checked
+        let regex = self._get_regex();
+        let mut count = 0;
+
+        let mut start = 0;
+        loop {
+            let next_special =
+                self._next_special(text, start, allowed_special, disallowed_special)?;
+            let end = next_special.map_or(text.len(), |m| m.start());
+
+            let segment = &text[start..end];
+            for range in self._pieces(&regex, segment) {
+                count += self._count_piece(segment[range].as_bytes());
+            }
+
+            match next_special {
+                Some(m) => {
+                    count += 1;
+                    start = m.end();
+                }
+                None => break,
+            }
+        }
+        Ok(count)
+    }
+
+    /// Encodes `text`, stopping once there are `max_tokens` tokens. A disallowed special token is
+    /// only an error if it comes before that.
+    #[allow(clippy::type_complexity)]
+    fn _encode_native_with_offsets(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+        max_tokens: usize,
+    ) -> Result<(Vec<Rank>, Vec<(usize, usize)>), DisallowedSpecialError> {
+        // Compare this logic to _encode_native_checked. The tokens of a piece are exactly its
+        // parts after merging, so each token's span follows from the lengths of the tokens
+        // before it.
+        let regex = self._get_regex();
+        let mut tokens = vec![];
+        let mut offsets = vec![];
+
+        let mut start = 0;
+        'outer: loop {
+            let next_special = self._find_special(text, start, allowed_special, disallowed_special);
+            let end = next_special.map_or(text.len(), |(m, _)| m.start());
+
+            let segment = &text[start..end];
+            for range in self._pieces(&regex, segment) {
+                if tokens.len() >= max_tokens {
+                    break 'outer;
+                }
+                let piece_start = start + range.start;
+                let piece = segment[range].as_bytes();
+                if let Some(token) = self._piece_token(piece) {
+                    tokens.push(token);
+                    offsets.push((piece_start, piece_start + piece.len()));
+                    continue;
+                }
+                let mut token_start = piece_start;
+                for token in self._byte_pair_encode(piece) {
+                    let token_end = token_start + self.decoder[&token].len();
+                    tokens.push(token);
+                    offsets.push((token_start, token_end));
+                    token_start = token_end;
+                }
+            }
+
+            match next_special {
+                Some((m, false)) if tokens.len() < max_tokens => {
+                    return Err(DisallowedSpecialError {
+                        token: text[m.range()].to_string(),
+                        offset: m.start(),
+                    })
+                }
+                Some((m, true)) if tokens.len() < max_tokens => {
+                    tokens.push(self.special_tokens_encoder[&text[m.range()]]);
+                    offsets.push((m.start(), m.end()));
+                    start = m.end();
+                }
+                _ => break,
+            }
+        }
+        tokens.truncate(max_tokens);
+        offsets.truncate(max_tokens);
+        Ok((tokens, offsets))
+    }
+
+    /// The index of the first of the last `max_tokens` tokens, moved forward if that token starts
+    /// inside a character
+    fn _tail_start(text: &str, offsets: &[(usize, usize)], max_tokens: usize) -> usize {
+        let mut start = offsets.len().saturating_sub(max_tokens);
+        while start < offsets.len() && !text.is_char_boundary(offsets[start].0) {
+            start += 1;
+        }
+        start
     }
 
     fn _increase_last_piece_token_len(
@@ -292,16 +835,46 @@ impl CoreBPE {
         (tokens, last_piece_token_len)
     }
 
+    fn _encode_bytes_native(&self, bytes: &[u8]) -> Vec<Rank> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => self._encode_ordinary_native(text),
+            Err(e) => {
+                let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
+                let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new());
+                let (mut tokens, last_piece_token_len) =
+                    self._increase_last_piece_token_len(tokens, last_piece_token_len);
+                if !tokens.is_empty() && last_piece_token_len > 0 {
+                    // Lop off the tokens from the last piece and run BPE on the remaining bytes
+                    // Somewhat niche, but this may not be correct if we'd have had a regex
+                    // split between the valid UTF-8 and the invalid bytes, which is why this
+                    // method is private
+                    let mut unstable_bytes =
+                        self._decode_native(&tokens[tokens.len() - last_piece_token_len..]);
+                    unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
+
+                    tokens.truncate(tokens.len() - last_piece_token_len);
+                    match self._piece_token(&unstable_bytes) {
+                        Some(token) => tokens.push(token),
+                        None => tokens.extend(&self._byte_pair_encode(&unstable_bytes)),
+                    }
+                }
+                tokens
+            }
+        }
+    }
+
     fn _encode_unstable_native(
         &self,
         text: &str,
-        allowed_special: &HashSet<&str>,
-    ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
-        let (tokens, last_piece_token_len) = self._encode_native(text, allowed_special);
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, HashSet<Vec<Rank>>), DisallowedSpecialError> {
+        let (tokens, last_piece_token_len) =
+            self._encode_native_checked(text, allowed_special, disallowed_special)?;
         if last_piece_token_len == 0 {
             // If last_piece_token_len is zero, the last token was a special token and we have
             // no unstable bytes
-            return (tokens, HashSet::new());
+            return Ok((tokens, HashSet::new()));
         }
         let (mut tokens, last_piece_token_len) =
             self._increase_last_piece_token_len(tokens, last_piece_token_len);
@@ -315,7 +888,7 @@ impl CoreBPE {
 
         let mut completions = HashSet::new();
         if unstable_bytes.is_empty() {
-            return (tokens, completions);
+            return Ok((tokens, completions));
         }
 
         // This is the easy bit. Just find all single tokens that start with unstable_bytes
@@ -360,7 +933,7 @@ impl CoreBPE {
                     // would be a regex split before the UTF-8 truncation point.
                     // Probably niche enough that no one will ever notice (after all, people didn't
                     // notice all the big holes in the previous unstable token implementation)
-                    Err(_) => byte_pair_encode(&possibility, &self.encoder),
+                    Err(_) => self._byte_pair_encode(&possibility),
                     // Something like the following is intriguing but incorrect:
                     // Err(e) => self._encode_ordinary_native(unsafe {
                     //     std::str::from_utf8_unchecked(&possibility[..e.valid_up_to()])
@@ -393,69 +966,179 @@ impl CoreBPE {
             if unstable_bytes.len() - last_decoded.1 > 0
                 && last_decoded.0.map_or(false, |c| c.is_whitespace())
             {
-                let mut reencoded = byte_pair_encode(
-                    &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
-                    &self.encoder,
+                let mut reencoded = self
+                    ._byte_pair_encode(&unstable_bytes[..unstable_bytes.len() - last_decoded.1]);
+                reencoded.extend(
+                    self._byte_pair_encode(
+                        &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
+                    ),
                 );
-                reencoded.extend(byte_pair_encode(
-                    &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
-                    &self.encoder,
-                ));
                 completions.insert(reencoded);
             }
         }
 
-        (tokens, completions)
+        Ok((tokens, completions))
     }
 }
 
-#[pymethods]
 impl CoreBPE {
-    #[new]
-    fn new(
+    pub fn new<E, SE>(
+        encoder: E,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, BuildError>
+    where
+        E: IntoIterator<Item = (Vec<u8>, Rank)>,
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        Self::with_backend(encoder, special_tokens_encoder, pattern, Backend::default())
+    }
+
+    pub fn with_backend<E, SE>(
+        encoder: E,
+        special_tokens_encoder: SE,
+        pattern: &str,
+        backend: Backend,
+    ) -> Result<Self, BuildError>
+    where
+        E: IntoIterator<Item = (Vec<u8>, Rank)>,
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder: HashMap<Vec<u8>, Rank> = encoder.into_iter().collect();
+        let special_tokens_encoder: HashMap<String, Rank> =
+            special_tokens_encoder.into_iter().collect();
+        _validate_vocab(&encoder, &special_tokens_encoder, pattern, true)
+            .map_err(BuildError::InvalidVocab)?;
+        Self::_build(encoder, special_tokens_encoder, pattern, backend)
+    }
+
+    /// The part of `with_backend` after validation
+    fn _build(
         encoder: HashMap<Vec<u8>, Rank>,
         special_tokens_encoder: HashMap<String, Rank>,
         pattern: &str,
-    ) -> PyResult<Self> {
-        let regex = Regex::new(pattern)
-            .map_err(|e| PyErr::new::<exceptions::PyValueError, _>(e.to_string()))?;
-
-        let special_regex = {
-            let _parts = special_tokens_encoder
-                .keys()
-                .map(|s| fancy_regex::escape(s))
-                .collect::<Vec<_>>();
-            Regex::new(&_parts.join("|"))
-                .map_err(|e| PyErr::new::<exceptions::PyValueError, _>(e.to_string()))?
-        };
+        backend: Backend,
+    ) -> Result<Self, BuildError> {
+        let regex = Regex::new(pattern)?;
 
         let decoder: HashMap<Rank, Vec<u8>> =
             encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
 
-        assert!(
-            encoder.len() == decoder.len(),
-            "Encoder and decoder must be of equal length; maybe you had duplicate token indices in your encoder?"
-        );
+        let (special_tokens_decoder, special_matcher) =
+            Self::_build_special(&special_tokens_encoder)?;
+
+        // Clone because I don't know how to tell Rust I'm not going to change the map
+        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
+        sorted_token_bytes.sort();
+
+        let linear_encoder = match backend {
+            Backend::Merge => None,
+            Backend::Linear => Some(Arc::new(LinearEncoder::new(&encoder)?)),
+        };
 
+        Ok(CoreBPE {
+            encoder: Arc::new(encoder),
+            special_tokens_encoder,
+            decoder: Arc::new(decoder),
+            special_tokens_decoder,
+            regex_pool: Arc::new(regex_pool(regex)),
+            special_matcher,
+            sorted_token_bytes: Arc::new(sorted_token_bytes),
+            linear_encoder,
+            merge_list: None,
+            keep_unmatched: false,
+        })
+    }
+
+    /// The parts of `_build` that depend on the special tokens
+    fn _build_special(
+        special_tokens_encoder: &HashMap<String, Rank>,
+    ) -> Result<(HashMap<Rank, Vec<u8>>, AhoCorasick), BuildError> {
         let special_tokens_decoder: HashMap<Rank, Vec<u8>> = special_tokens_encoder
             .iter()
             .map(|(k, v)| (*v, k.as_bytes().to_vec()))
             .collect();
 
-        // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
-        sorted_token_bytes.sort();
+        // Standard rather than leftmost-longest, because `_find_allowed_special` needs every
+        // match to be able to skip the ones that aren't allowed
+        let special_matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::Standard)
+            .build(special_tokens_encoder.keys().filter(|s| !s.is_empty()))
+            .map_err(|e| BuildError::UnsupportedVocab(e.to_string()))?;
+
+        Ok((special_tokens_decoder, special_matcher))
+    }
 
+    /// Builds a `CoreBPE` whose merges are driven by an explicit merge list rather than by
+    /// ranks, so token values in `encoder` don't have to follow merge priority. `merges` are
+    /// pairs of token bytes, highest priority first, see `MergeList::new`.
+    pub fn from_merges<E, M, SE>(
+        encoder: E,
+        merges: M,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, BuildError>
+    where
+        E: IntoIterator<Item = (Vec<u8>, Rank)>,
+        M: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder: HashMap<Vec<u8>, Rank> = encoder.into_iter().collect();
+        let special_tokens_encoder: HashMap<String, Rank> =
+            special_tokens_encoder.into_iter().collect();
+        _validate_vocab(&encoder, &special_tokens_encoder, pattern, false)
+            .map_err(BuildError::InvalidVocab)?;
+        let merge_list = MergeList::new(&encoder, merges)?;
+        let mut bpe = Self::_build(encoder, special_tokens_encoder, pattern, Backend::Merge)?;
+        bpe.merge_list = Some(Arc::new(merge_list));
+        Ok(bpe)
+    }
+
+    /// For loaders that know whether their merges ignore whole tokens, see
+    /// `MergeList::ignore_merges`. Only has an effect right after `from_merges`, before anything
+    /// shares the merge list.
+    pub(crate) fn _set_ignore_merges(&mut self, ignore_merges: bool) {
+        if let Some(merge_list) = self.merge_list.as_mut().and_then(Arc::get_mut) {
+            merge_list.ignore_merges = ignore_merges;
+        }
+    }
+
+    /// For loaders whose pattern doesn't have to match all of the text, see `keep_unmatched`.
+    pub(crate) fn _set_keep_unmatched(&mut self, keep_unmatched: bool) {
+        self.keep_unmatched = keep_unmatched;
+    }
+
+    /// Builds a `CoreBPE` with the same ordinary tokens and pattern, with the special tokens in
+    /// `remove` removed and those in `add` added. Adding a special token that already exists
+    /// changes its rank. The ordinary tokens are shared rather than copied, which makes this
+    /// much cheaper than `new` for big vocabularies.
+    pub fn with_special_tokens<SE>(&self, add: SE, remove: &[&str]) -> Result<Self, BuildError>
+    where
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let mut special_tokens_encoder = self.special_tokens_encoder.clone();
+        for token in remove {
+            special_tokens_encoder.remove(*token);
+        }
+        special_tokens_encoder.extend(add);
+        let errors = special_token_collisions(&self.decoder, &special_tokens_encoder);
+        if !errors.is_empty() {
+            return Err(BuildError::InvalidVocab(errors));
+        }
+
+        let (special_tokens_decoder, special_matcher) =
+            Self::_build_special(&special_tokens_encoder)?;
         Ok(CoreBPE {
-            encoder,
+            encoder: Arc::clone(&self.encoder),
             special_tokens_encoder,
-            decoder,
+            decoder: Arc::clone(&self.decoder),
             special_tokens_decoder,
-            regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
-            special_regex_tls: (0..MAX_NUM_THREADS)
-                .map(|_| special_regex.clone())
-                .collect(),
-            sorted_token_bytes,
+            regex_pool: Arc::clone(&self.regex_pool),
+            special_matcher,
+            sorted_token_bytes: Arc::clone(&self.sorted_token_bytes),
+            linear_encoder: self.linear_encoder.clone(),
+            merge_list: self.merge_list.clone(),
+            keep_unmatched: self.keep_unmatched,
         })
     }
 
@@ -463,118 +1146,392 @@ impl CoreBPE {
     // Encoding
     // ====================
 
-    fn encode_ordinary(&self, py: Python, text: &str) -> Vec<Rank> {
-        py.allow_threads(|| self._encode_ordinary_native(text))
-    }
-
-    fn encode(&self, py: Python, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
-        py.allow_threads(|| self._encode_native(text, &allowed_special).0)
-    }
-
-    fn _encode_bytes(&self, py: Python, bytes: &[u8]) -> Vec<Rank> {
-        py.allow_threads(|| {
-            match std::str::from_utf8(bytes) {
-                Ok(text) => self._encode_ordinary_native(text),
-                Err(e) => {
-                    let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
-                    let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new());
-                    let (mut tokens, last_piece_token_len) =
-                        self._increase_last_piece_token_len(tokens, last_piece_token_len);
-                    if !tokens.is_empty() && last_piece_token_len > 0 {
-                        // Lop off the tokens from the last piece and run BPE on the remaining bytes
-                        // Somewhat niche, but this may not be correct if we'd have had a regex
-                        // split between the valid UTF-8 and the invalid bytes, which is why this
-                        // method is private
-                        let mut unstable_bytes =
-                            self._decode_native(&tokens[tokens.len() - last_piece_token_len..]);
-                        unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
-
-                        tokens.truncate(tokens.len() - last_piece_token_len);
-                        match self.encoder.get(&unstable_bytes) {
-                            Some(token) => tokens.push(*token),
-                            None => tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)),
-                        }
-                    }
-                    tokens
-                }
-            }
-        })
+    pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+        self._encode_ordinary_native(text)
+    }
+
+    pub fn encode(&self, text: &str, allowed_special: &HashSet<&str>) -> Vec<Rank> {
+        self._encode_native(text, allowed_special).0
+    }
+
+    /// Like `encode`, but returns an error for the first disallowed special token in `text`,
+    /// found in the same pass as the encoding. Special tokens that are neither allowed nor
+    /// disallowed are ordinary text. A special token that is both is disallowed.
+    ///
+    /// Disallowed special tokens compete with allowed ones like in `_find_allowed_special`, so a
+    /// disallowed special token that is part of a longer allowed one is fine, but an allowed
+    /// special token that is part of a longer disallowed one isn't.
+    pub fn encode_checked(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<Vec<Rank>, DisallowedSpecialError> {
+        Ok(self
+            ._encode_native_checked(text, allowed_special, disallowed_special)?
+            .0)
+    }
+
+    /// Like `encode`, but `policy` says what happens to special tokens, see `SpecialTokenPolicy`.
+    /// Only `SpecialTokenPolicy::Raise` ever returns an error.
+    pub fn encode_with_policy(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        policy: SpecialTokenPolicy,
+    ) -> Result<Vec<Rank>, DisallowedSpecialError> {
+        match policy {
+            SpecialTokenPolicy::Allow => Ok(self.encode(text, allowed_special)),
+            SpecialTokenPolicy::Raise => self.encode_checked(
+                text,
+                SpecialTokenSet::Only(allowed_special),
+                SpecialTokenSet::All,
+            ),
+            SpecialTokenPolicy::TreatAsText => Ok(self.encode_ordinary(text)),
+        }
+    }
+
+    /// Like `encode`, but also returns the span of bytes in `text` that each token came from.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, Vec<(usize, usize)>) {
+        self.encode_with_offsets_checked(
+            text,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
+    }
+
+    /// Like `encode_with_offsets`, but disallowed special tokens are an error like in
+    /// `encode_checked`.
+    #[allow(clippy::type_complexity)]
+    pub fn encode_with_offsets_checked(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, Vec<(usize, usize)>), DisallowedSpecialError> {
+        self._encode_native_with_offsets(text, allowed_special, disallowed_special, usize::MAX)
+    }
+
+    /// Encodes the longest prefix of `text` that fits in `max_tokens` tokens. Returns those
+    /// tokens, which are the first tokens of `encode(text, allowed_special)`, and the byte offset
+    /// in `text` where the prefix ends. The cut is at both a token and a char boundary, so it
+    /// may keep fewer than `max_tokens` tokens if the last one would end inside a character.
+    pub fn encode_truncated(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, usize) {
+        self.encode_truncated_checked(
+            text,
+            max_tokens,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
+    }
+
+    /// Like `encode_truncated`, but disallowed special tokens are an error like in
+    /// `encode_checked`. Only the text that fits in `max_tokens` is checked.
+    pub fn encode_truncated_checked(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, usize), DisallowedSpecialError> {
+        let (mut tokens, offsets) = self._encode_native_with_offsets(
+            text,
+            allowed_special,
+            disallowed_special,
+            max_tokens,
+        )?;
+        let mut num_kept = tokens.len();
+        while num_kept > 0 && !text.is_char_boundary(offsets[num_kept - 1].1) {
+            num_kept -= 1;
+        }
+        tokens.truncate(num_kept);
+        let cut = num_kept.checked_sub(1).map_or(0, |i| offsets[i].1);
+        Ok((tokens, cut))
+    }
+
+    /// Like `encode_truncated`, but keeps the end of `text` instead. Returns the last tokens of
+    /// `encode(text, allowed_special)` and the byte offset in `text` where they start. This has
+    /// to encode all of `text`, since the regex splits can only be found from the start.
+    pub fn encode_truncated_tail(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, usize) {
+        self.encode_truncated_tail_checked(
+            text,
+            max_tokens,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
+    }
+
+    /// Like `encode_truncated_tail`, but disallowed special tokens are an error like in
+    /// `encode_checked`.
+    pub fn encode_truncated_tail_checked(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, usize), DisallowedSpecialError> {
+        let (mut tokens, offsets) =
+            self.encode_with_offsets_checked(text, allowed_special, disallowed_special)?;
+        let num_dropped = Self::_tail_start(text, &offsets, max_tokens);
+        let start = offsets.get(num_dropped).map_or(text.len(), |o| o.0);
+        Ok((tokens.split_off(num_dropped), start))
+    }
+
+    /// Keeps the start and the end of `text` and drops the middle, so that at most `max_tokens`
+    /// tokens remain. The start gets the larger half of the budget. Returns the tokens of the
+    /// start, the tokens of the end, and the byte range of `text` that was dropped (which is
+    /// empty at the end of `text` if everything fits), so that the caller can put a marker in
+    /// between.
+    pub fn encode_truncated_middle(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, Vec<Rank>, (usize, usize)) {
+        self.encode_truncated_middle_checked(
+            text,
+            max_tokens,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
     }
 
-    fn encode_with_unstable(
+    /// Like `encode_truncated_middle`, but disallowed special tokens are an error like in
+    /// `encode_checked`, including in the middle that gets dropped.
+    #[allow(clippy::type_complexity)]
+    pub fn encode_truncated_middle_checked(
         &self,
-        py: Python,
         text: &str,
-        allowed_special: HashSet<&str>,
-    ) -> Py<PyTuple> {
-        let (tokens, completions) =
-            py.allow_threads(|| self._encode_unstable_native(text, &allowed_special));
-        let py_completions =
-            PyList::new(py, completions.iter().map(|seq| PyList::new(py, &seq[..])));
-        (tokens, py_completions).into_py(py)
+        max_tokens: usize,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, Vec<Rank>, (usize, usize)), DisallowedSpecialError> {
+        let (mut tokens, offsets) =
+            self.encode_with_offsets_checked(text, allowed_special, disallowed_special)?;
+        if tokens.len() <= max_tokens {
+            return Ok((tokens, vec![], (text.len(), text.len())));
+        }
+        let mut num_head = max_tokens - max_tokens / 2;
+        while num_head > 0 && !text.is_char_boundary(offsets[num_head - 1].1) {
+            num_head -= 1;
+        }
+        let tail_start = Self::_tail_start(text, &offsets, max_tokens / 2);
+        let dropped = (
+            num_head.checked_sub(1).map_or(0, |i| offsets[i].1),
+            offsets.get(tail_start).map_or(text.len(), |o| o.0),
+        );
+        let tail = tokens.split_off(tail_start);
+        tokens.truncate(num_head);
+        Ok((tokens, tail, dropped))
+    }
+
+    /// Same as `encode_ordinary(text).len()`, but doesn't build the tokens.
+    pub fn count_tokens_ordinary(&self, text: &str) -> usize {
+        let regex = self._get_regex();
+        self._pieces(&regex, text)
+            .map(|range| self._count_piece(text[range].as_bytes()))
+            .sum()
+    }
+
+    /// Same as `encode(text, allowed_special).len()`, but doesn't build the tokens.
+    pub fn count_tokens(&self, text: &str, allowed_special: &HashSet<&str>) -> usize {
+        self.count_tokens_checked(
+            text,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
     }
 
-    fn encode_single_token(&self, piece: &[u8]) -> PyResult<Rank> {
+    /// Same as `encode_checked(text, ...).map(|tokens| tokens.len())`, but doesn't build the
+    /// tokens.
+    pub fn count_tokens_checked(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<usize, DisallowedSpecialError> {
+        self._count_native(text, allowed_special, disallowed_special)
+    }
+
+    pub fn encode_with_unstable(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
+        self.encode_with_unstable_checked(
+            text,
+            SpecialTokenSet::Only(allowed_special),
+            SpecialTokenSet::Only(&HashSet::new()),
+        )
+        .unwrap_or_else(|_| unreachable!("nothing is disallowed"))
+    }
+
+    /// Like `encode_with_unstable`, but disallowed special tokens are an error like in
+    /// `encode_checked`.
+    pub fn encode_with_unstable_checked(
+        &self,
+        text: &str,
+        allowed_special: SpecialTokenSet,
+        disallowed_special: SpecialTokenSet,
+    ) -> Result<(Vec<Rank>, HashSet<Vec<Rank>>), DisallowedSpecialError> {
+        self._encode_unstable_native(text, allowed_special, disallowed_special)
+    }
+
+    pub fn encode_single_token(&self, piece: &[u8]) -> Option<Rank> {
         if let Some(token) = self.encoder.get(piece).copied() {
-            return Ok(token);
+            return Some(token);
         }
         if let Ok(piece_str) = std::str::from_utf8(piece) {
             if let Some(token) = self.special_tokens_encoder.get(piece_str).copied() {
-                return Ok(token);
+                return Some(token);
             }
         }
-        Err(PyErr::new::<exceptions::PyKeyError, _>(piece.to_owned()))
+        None
     }
 
-    fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
-        if let Some(token) = self.encoder.get(piece) {
-            return vec![*token];
+    pub fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
+        if let Some(token) = self._piece_token(piece) {
+            return vec![token];
         }
-        byte_pair_encode(piece, &self.encoder)
+        self._byte_pair_encode(piece)
     }
 
     // ====================
     // Decoding
     // ====================
 
-    fn decode_bytes(&self, py: Python, tokens: Vec<Rank>) -> Py<PyBytes> {
-        let bytes = py.allow_threads(|| self._decode_native(&tokens));
-        PyBytes::new(py, &bytes).into()
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, DecodeError> {
+        self._decode_checked(tokens, UnknownTokenPolicy::Raise)
+    }
+
+    /// Like `decode_bytes`, but `unknown` decides what happens to tokens that aren't in the
+    /// vocabulary. Only `UnknownTokenPolicy::Raise` ever returns an error.
+    pub fn decode_bytes_with_policy(
+        &self,
+        tokens: &[Rank],
+        unknown: UnknownTokenPolicy,
+    ) -> Result<Vec<u8>, DecodeError> {
+        self._decode_checked(tokens, unknown)
     }
 
-    fn decode_single_token_bytes(&self, py: Python, token: Rank) -> PyResult<Py<PyBytes>> {
+    pub fn decode_single_token_bytes(&self, token: Rank) -> Option<&[u8]> {
         if let Some(bytes) = self.decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
+            return Some(bytes);
         }
         if let Some(bytes) = self.special_tokens_decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
+            return Some(bytes);
         }
-        Err(PyErr::new::<exceptions::PyKeyError, _>(token.to_string()))
+        None
     }
 
     // ====================
     // Miscellaneous
     // ====================
 
-    fn token_byte_values(&self, py: Python) -> Vec<Py<PyBytes>> {
-        self.sorted_token_bytes
-            .iter()
-            .map(|x| PyBytes::new(py, x).into())
+    pub fn backend(&self) -> Backend {
+        match self.linear_encoder {
+            Some(_) => Backend::Linear,
+            None => Backend::Merge,
+        }
+    }
+
+    /// The explicit merge list, if this was built with `from_merges`.
+    pub fn merge_list(&self) -> Option<&MergeList> {
+        self.merge_list.as_deref()
+    }
+
+    pub fn special_tokens(&self) -> HashSet<&str> {
+        self.special_tokens_encoder
+            .keys()
+            .map(|s| s.as_str())
             .collect()
     }
-}
 
-#[pymodule]
-fn _tiktoken(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<CoreBPE>()?;
-    Ok(())
+    pub fn token_byte_values(&self) -> &[Vec<u8>] {
+        &self.sorted_token_bytes
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
     use rustc_hash::FxHashMap as HashMap;
 
-    use crate::{byte_pair_split, Rank};
+    use crate::merges::derive_merges;
+    use crate::validate::VocabError;
+    use crate::{
+        _byte_pair_merge_large, _byte_pair_merge_small, byte_offsets_to_char_offsets,
+        byte_pair_split, Backend, BuildError, CoreBPE, DecodeError, DisallowedSpecialError, Rank,
+        SpecialTokenPolicy, SpecialTokenSet, UnknownTokenPolicy,
+    };
+
+    pub(crate) const R50K_PATTERN: &str =
+        r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+    pub(crate) const CL100K_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+    pub(crate) const O200K_PATTERN: &str = concat!(
+        r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+    );
+
+    /// A deterministic pseudo-random number generator for tests.
+    pub(crate) struct Lcg(pub(crate) u64);
+
+    impl Lcg {
+        pub(crate) fn next(&mut self) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (self.0 >> 33) as usize
+        }
+    }
+
+    /// All single bytes, then `tokens` in order of rank.
+    pub(crate) fn setup_encoder(tokens: &[&[u8]]) -> HashMap<Vec<u8>, Rank> {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b as Rank)).collect();
+        for token in tokens {
+            let rank = encoder.len() as Rank;
+            encoder.insert(token.to_vec(), rank);
+        }
+        encoder
+    }
+
+    /// A small vocabulary that uses cl100k_base's pattern, with all single bytes, some merges and
+    /// some special tokens.
+    pub(crate) fn setup_core_bpe() -> CoreBPE {
+        const MERGES: &[&str] = &[
+            "  ", "   ", "    ", "\n\n", " \n", " t", "he", " the", "in", "ing", " a", "er", "on",
+            "re", "or", "at", "en", "is", " s", " w", "ll", " o", "an", "ou", "es", "lo", "el",
+            "hel", "hello", "ld", " wor", " world", "é", "<|", "|>", "12", "123", "'s", "it",
+        ];
+        let merges: Vec<&[u8]> = MERGES.iter().map(|merge| merge.as_bytes()).collect();
+        let encoder = setup_encoder(&merges);
+        let special_tokens_encoder = [
+            ("<|endoftext|>".to_string(), 1000),
+            ("<|fim_prefix|>".to_string(), 1001),
+        ];
+        CoreBPE::new(encoder, special_tokens_encoder, CL100K_PATTERN).unwrap()
+    }
 
     fn setup_ranks() -> HashMap<Vec<u8>, Rank> {
         HashMap::from_iter([
@@ -596,4 +1553,532 @@ mod tests {
         let res = byte_pair_split(b"abab", &ranks);
         assert_eq!(res, vec![b"ab", b"ab"]);
     }
+
+    #[test]
+    fn test_core_bpe_roundtrip() {
+        let encoder = setup_encoder(&[b"ab", b"cd"]);
+        let special_tokens_encoder = HashMap::from_iter([("<|end|>".to_string(), 258)]);
+        let bpe = CoreBPE::new(encoder, special_tokens_encoder, r"\w+|\s+|[^\w\s]+").unwrap();
+
+        let allowed_special = HashSet::from(["<|end|>"]);
+        let tokens = bpe.encode("abcd ab<|end|>", &allowed_special);
+        assert_eq!(tokens, vec![256, 257, 32, 256, 258]);
+        assert_eq!(bpe.decode_bytes(&tokens).unwrap(), b"abcd ab<|end|>");
+        assert_eq!(bpe.encode_ordinary("<|end|>").len(), 7);
+    }
+
+    /// Byte tokens, and special tokens that overlap in "<|im_start|>user<|im_end|>"
+    fn setup_overlapping_specials() -> CoreBPE {
+        let encoder = setup_encoder(&[]);
+        let special_tokens_encoder = [
+            ("<|im_start|>".to_string(), 1000),
+            ("<|im_start|>user".to_string(), 1001),
+            ("user<|im_end|>".to_string(), 1002),
+            ("<|im_end|>".to_string(), 1003),
+        ];
+        CoreBPE::new(encoder, special_tokens_encoder, r"\S+|\s+").unwrap()
+    }
+
+    #[test]
+    fn test_overlapping_special_tokens() {
+        let bpe = setup_overlapping_specials();
+        let text = "<|im_start|>user<|im_end|>";
+
+        let all = HashSet::from([
+            "<|im_start|>",
+            "<|im_start|>user",
+            "user<|im_end|>",
+            "<|im_end|>",
+        ]);
+        // The leftmost match wins, and the longest of those
+        assert_eq!(bpe.encode(text, &all), vec![1001, 1003]);
+
+        let allowed = HashSet::from(["<|im_start|>", "user<|im_end|>"]);
+        assert_eq!(bpe.encode(text, &allowed), vec![1000, 1002]);
+
+        // Disallowed special tokens are ordinary text, even when they overlap allowed ones
+        let allowed = HashSet::from(["<|im_end|>"]);
+        let mut expected = bpe.encode_ordinary("<|im_start|>user");
+        expected.push(1003);
+        assert_eq!(bpe.encode(text, &allowed), expected);
+        assert_eq!(bpe.count_tokens(text, &allowed), expected.len());
+    }
+
+    #[test]
+    fn test_encode_checked() {
+        let bpe = setup_overlapping_specials();
+        let text = "hi <|im_start|>user<|im_end|>";
+        let none = HashSet::new();
+        let im_start = HashSet::from(["<|im_start|>"]);
+        let im_start_user = HashSet::from(["<|im_start|>user"]);
+
+        assert_eq!(
+            bpe.encode_checked(text, SpecialTokenSet::All, SpecialTokenSet::All),
+            Ok(bpe.encode(text, &bpe.special_tokens()))
+        );
+        assert_eq!(
+            bpe.encode_checked(
+                text,
+                SpecialTokenSet::Only(&none),
+                SpecialTokenSet::Only(&none)
+            ),
+            Ok(bpe.encode_ordinary(text))
+        );
+        assert_eq!(
+            bpe.encode_checked(text, SpecialTokenSet::Only(&none), SpecialTokenSet::All),
+            Err(DisallowedSpecialError {
+                token: "<|im_start|>user".to_string(),
+                offset: 3,
+            })
+        );
+        // "<|im_start|>user" beats "<|im_start|>", and then "<|im_end|>" is disallowed
+        assert_eq!(
+            bpe.encode_checked(text, SpecialTokenSet::Only(&im_start), SpecialTokenSet::All),
+            Err(DisallowedSpecialError {
+                token: "<|im_start|>user".to_string(),
+                offset: 3,
+            })
+        );
+        assert_eq!(
+            bpe.encode_checked(
+                text,
+                SpecialTokenSet::Only(&im_start_user),
+                SpecialTokenSet::All
+            ),
+            Err(DisallowedSpecialError {
+                token: "<|im_end|>".to_string(),
+                offset: 19,
+            })
+        );
+        // Disallowed wins over allowed
+        assert_eq!(
+            bpe.encode_checked(
+                text,
+                SpecialTokenSet::All,
+                SpecialTokenSet::Only(&HashSet::from(["<|im_end|>"]))
+            ),
+            Err(DisallowedSpecialError {
+                token: "<|im_end|>".to_string(),
+                offset: 19,
+            })
+        );
+        // Special tokens that are neither allowed nor disallowed are text
+        assert_eq!(
+            bpe.encode_checked(
+                text,
+                SpecialTokenSet::Only(&im_start_user),
+                SpecialTokenSet::Only(&im_start)
+            ),
+            Ok(bpe.encode(text, &im_start_user))
+        );
+
+        for (allowed, disallowed) in [
+            (SpecialTokenSet::All, SpecialTokenSet::All),
+            (SpecialTokenSet::Only(&none), SpecialTokenSet::All),
+            (SpecialTokenSet::Only(&im_start), SpecialTokenSet::All),
+            (SpecialTokenSet::Only(&im_start_user), SpecialTokenSet::All),
+            (
+                SpecialTokenSet::Only(&im_start_user),
+                SpecialTokenSet::Only(&im_start),
+            ),
+        ] {
+            let expected = bpe.encode_checked(text, allowed, disallowed);
+            assert_eq!(
+                bpe.count_tokens_checked(text, allowed, disallowed),
+                expected.clone().map(|tokens| tokens.len())
+            );
+            assert_eq!(
+                bpe.encode_with_offsets_checked(text, allowed, disallowed)
+                    .map(|(tokens, _)| tokens),
+                expected
+            );
+            assert_eq!(
+                bpe.encode_truncated_tail_checked(text, 2, allowed, disallowed)
+                    .map(|(tokens, _)| tokens.len()),
+                expected.clone().map(|tokens| tokens.len().min(2))
+            );
+            assert_eq!(
+                bpe.encode_with_unstable_checked(text, allowed, disallowed)
+                    .err(),
+                expected.err()
+            );
+        }
+        // Truncating only checks what it encodes
+        assert_eq!(
+            bpe.encode_truncated_checked(
+                text,
+                2,
+                SpecialTokenSet::Only(&none),
+                SpecialTokenSet::All
+            ),
+            Ok(bpe.encode_truncated(text, 2, &none))
+        );
+    }
+
+    #[test]
+    fn test_encode_with_policy() {
+        let bpe = setup_core_bpe();
+        let text = "hello <|endoftext|> world <|fim_prefix|>";
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+
+        assert_eq!(
+            bpe.encode_with_policy(text, &allowed_special, SpecialTokenPolicy::Allow),
+            Ok(bpe.encode(text, &allowed_special))
+        );
+        assert_eq!(
+            bpe.encode_with_policy(text, &allowed_special, SpecialTokenPolicy::Raise),
+            Err(DisallowedSpecialError {
+                token: "<|fim_prefix|>".to_string(),
+                offset: 26,
+            })
+        );
+        let tokens = bpe
+            .encode_with_policy(text, &bpe.special_tokens(), SpecialTokenPolicy::TreatAsText)
+            .unwrap();
+        assert_eq!(tokens, bpe.encode_ordinary(text));
+        assert!(tokens.iter().all(|token| *token < 1000));
+    }
+
+    #[test]
+    fn test_with_special_tokens() {
+        let bpe = setup_core_bpe();
+        let text = "hello <|endoftext|> world <|im_start|>";
+        let derived = bpe
+            .with_special_tokens([("<|im_start|>".to_string(), 1002)], &["<|endoftext|>"])
+            .unwrap();
+        assert!(Arc::ptr_eq(&bpe.encoder, &derived.encoder));
+        assert!(Arc::ptr_eq(
+            &bpe.sorted_token_bytes,
+            &derived.sorted_token_bytes
+        ));
+        assert_eq!(
+            derived.special_tokens(),
+            HashSet::from(["<|fim_prefix|>", "<|im_start|>"])
+        );
+
+        let tokens = derived.encode(text, &derived.special_tokens());
+        assert_eq!(tokens.last(), Some(&1002));
+        assert_eq!(
+            tokens[..tokens.len() - 1],
+            bpe.encode_ordinary("hello <|endoftext|> world ")
+        );
+        assert_eq!(derived.decode_bytes(&tokens).unwrap(), text.as_bytes());
+        assert!(derived.decode_bytes(&[1000]).is_err());
+        // The original is unchanged
+        assert_eq!(
+            bpe.encode("<|endoftext|>", &bpe.special_tokens()),
+            vec![1000]
+        );
+
+        match bpe.with_special_tokens(
+            [
+                ("<|a|>".to_string(), b'a' as Rank),
+                ("<|b|>".to_string(), 1001),
+            ],
+            &[],
+        ) {
+            Err(BuildError::InvalidVocab(errors)) => assert_eq!(
+                errors,
+                vec![
+                    VocabError::SpecialTokenCollision {
+                        special_token: "<|a|>".to_string(),
+                        rank: b'a' as Rank,
+                        other: b"a".to_vec(),
+                    },
+                    VocabError::SpecialTokenCollision {
+                        special_token: "<|fim_prefix|>".to_string(),
+                        rank: 1001,
+                        other: b"<|b|>".to_vec(),
+                    },
+                ]
+            ),
+            _ => panic!("expected InvalidVocab"),
+        }
+        // Moving a special token to a rank that was just freed up is fine
+        let moved = bpe
+            .with_special_tokens([("<|fim_prefix|>".to_string(), 1000)], &["<|endoftext|>"])
+            .unwrap();
+        assert_eq!(
+            moved.encode("<|fim_prefix|>", &moved.special_tokens()),
+            vec![1000]
+        );
+    }
+
+    #[test]
+    fn test_decode_unknown_tokens() {
+        let bpe = setup_core_bpe();
+        let tokens = [b'h' as Rank, 5000, b'i' as Rank, 1000];
+        assert_eq!(
+            bpe.decode_bytes(&tokens),
+            Err(DecodeError {
+                token: 5000,
+                position: 1,
+                batch_index: None,
+            })
+        );
+        assert_eq!(
+            bpe.decode_bytes_with_policy(&tokens, UnknownTokenPolicy::Skip)
+                .unwrap(),
+            b"hi<|endoftext|>"
+        );
+        assert_eq!(
+            bpe.decode_bytes_with_policy(&tokens, UnknownTokenPolicy::Replace)
+                .unwrap(),
+            "h\u{fffd}i<|endoftext|>".as_bytes()
+        );
+
+        let batch = vec![vec![b'h' as Rank], vec![1001, 1002]];
+        let e = bpe.decode_batch(&batch, 2).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid token 1002 at position 1 of batch item 1"
+        );
+        assert_eq!(
+            bpe.decode_batch_with_policy(&batch, UnknownTokenPolicy::Skip, 2)
+                .unwrap(),
+            vec![b"h".to_vec(), b"<|fim_prefix|>".to_vec()]
+        );
+
+        let mut decoder = bpe.streaming_decoder();
+        assert!(decoder.push(&[b'h' as Rank, 5000]).is_err());
+        assert!(decoder.pending().is_empty());
+    }
+
+    #[test]
+    fn test_core_bpe_linear_backend() {
+        let ranks = setup_dense_ranks();
+        let merge = CoreBPE::new(ranks.clone(), [], r"\S+|\s+").unwrap();
+        let linear = CoreBPE::with_backend(ranks, [], r"\S+|\s+", Backend::Linear).unwrap();
+        assert_eq!(merge.backend(), Backend::Merge);
+        assert_eq!(linear.backend(), Backend::Linear);
+        let text = "abba ba\n\n  aaaa bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb abab";
+        assert_eq!(merge.encode_ordinary(text), linear.encode_ordinary(text));
+    }
+
+    /// A vocabulary over a tiny alphabet, so that long pieces go through many merges.
+    fn setup_dense_ranks() -> HashMap<Vec<u8>, Rank> {
+        let alphabet = b"ab \n";
+        let mut tokens: Vec<Vec<u8>> = vec![vec![]];
+        let mut ranks = HashMap::default();
+        let mut rng = Lcg(42);
+        for _ in 0..5 {
+            tokens = tokens
+                .iter()
+                .flat_map(|t| {
+                    alphabet.iter().map(move |&c| {
+                        let mut t = t.clone();
+                        t.push(c);
+                        t
+                    })
+                })
+                .collect();
+            for token in &tokens {
+                let keep = rng.next() >> 29 != 0;
+                // Skip some tokens so that not every pair is mergeable, but keep every token
+                // reachable by merges
+                let reachable = (1..token.len())
+                    .any(|i| ranks.contains_key(&token[..i]) && ranks.contains_key(&token[i..]));
+                if token.len() == 1 || (reachable && keep) {
+                    let rank = ranks.len() as Rank;
+                    ranks.insert(token.clone(), rank);
+                }
+            }
+        }
+        for b in 0..=255u8 {
+            if !ranks.contains_key(&[b][..]) {
+                let rank = ranks.len() as Rank;
+                ranks.insert(vec![b], rank);
+            }
+        }
+        ranks
+    }
+
+    #[test]
+    fn test_byte_pair_merge_large_matches_small() {
+        let ranks = setup_dense_ranks();
+        let alphabet = b"ab \n";
+        let mut rng = Lcg(7);
+        for len in [2, 3, 10, 50, 127, 128, 129, 300, 1000] {
+            for _ in 0..20 {
+                let piece: Vec<u8> = (0..len)
+                    .map(|_| alphabet[rng.next() % alphabet.len()])
+                    .collect();
+                assert_eq!(
+                    _byte_pair_merge_small(&ranks, &piece),
+                    _byte_pair_merge_large(&ranks, &piece),
+                    "{:?}",
+                    piece
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_pair_merge_large_repeated() {
+        let ranks = setup_dense_ranks();
+        for piece in [vec![b' '; 1000], vec![b'a'; 999], b"ab".repeat(500)] {
+            assert_eq!(
+                _byte_pair_merge_small(&ranks, &piece),
+                _byte_pair_merge_large(&ranks, &piece)
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let bpe = setup_core_bpe();
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let text = "hello wörld<|endoftext|> 日本";
+        let (tokens, offsets) = bpe.encode_with_offsets(text, &allowed_special);
+        assert_eq!(tokens, bpe.encode(text, &allowed_special));
+        assert_eq!(tokens.len(), offsets.len());
+        for (token, &(start, end)) in tokens.iter().zip(&offsets) {
+            assert_eq!(
+                bpe.decode_single_token_bytes(*token).unwrap(),
+                &text.as_bytes()[start..end]
+            );
+        }
+        assert_eq!(offsets[..3], [(0, 5), (5, 7), (7, 8)]);
+        assert_eq!(offsets.last(), Some(&(text.len() - 1, text.len())));
+    }
+
+    #[test]
+    fn test_byte_offsets_to_char_offsets() {
+        // "日" is 3 bytes, split across two tokens
+        let text = "a日b";
+        let offsets = [(0, 1), (1, 3), (3, 4), (4, 5)];
+        assert_eq!(
+            byte_offsets_to_char_offsets(text, &offsets),
+            vec![(0, 1), (1, 2), (1, 2), (2, 3)]
+        );
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let texts = [
+            "",
+            "hello world",
+            "héllo 日本語 <|endoftext|><|fim_prefix|>\n\n  ",
+            &"ab".repeat(200),
+        ];
+        let bpe = setup_core_bpe();
+        let merges = derive_merges(&bpe.encoder).merges;
+        let bpes = [
+            CoreBPE::with_backend(
+                (*bpe.encoder).clone(),
+                bpe.special_tokens_encoder.clone(),
+                CL100K_PATTERN,
+                Backend::Merge,
+            )
+            .unwrap(),
+            CoreBPE::with_backend(
+                (*bpe.encoder).clone(),
+                bpe.special_tokens_encoder.clone(),
+                CL100K_PATTERN,
+                Backend::Linear,
+            )
+            .unwrap(),
+            CoreBPE::from_merges(
+                (*bpe.encoder).clone(),
+                merges,
+                bpe.special_tokens_encoder.clone(),
+                CL100K_PATTERN,
+            )
+            .unwrap(),
+        ];
+        for bpe in &bpes {
+            for text in texts {
+                assert_eq!(
+                    bpe.count_tokens_ordinary(text),
+                    bpe.encode_ordinary(text).len()
+                );
+                assert_eq!(
+                    bpe.count_tokens(text, &allowed_special),
+                    bpe.encode(text, &allowed_special).len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        let bpe = setup_core_bpe();
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let text = "hello world<|endoftext|> 日本 wörld";
+        let (tokens, offsets) = bpe.encode_with_offsets(text, &allowed_special);
+
+        for max_tokens in 0..tokens.len() + 2 {
+            let (head, cut) = bpe.encode_truncated(text, max_tokens, &allowed_special);
+            assert!(head.len() <= max_tokens);
+            assert_eq!(head, tokens[..head.len()]);
+            assert_eq!(
+                String::from_utf8(bpe.decode_bytes(&head).unwrap()).unwrap(),
+                &text[..cut]
+            );
+
+            let (tail, start) = bpe.encode_truncated_tail(text, max_tokens, &allowed_special);
+            assert!(tail.len() <= max_tokens);
+            assert_eq!(tail, tokens[tokens.len() - tail.len()..]);
+            assert_eq!(
+                String::from_utf8(bpe.decode_bytes(&tail).unwrap()).unwrap(),
+                &text[start..]
+            );
+
+            let (head, tail, (cut, start)) =
+                bpe.encode_truncated_middle(text, max_tokens, &allowed_special);
+            assert!(head.len() + tail.len() <= max_tokens);
+            assert!(cut <= start);
+            assert_eq!(
+                String::from_utf8(bpe.decode_bytes(&head).unwrap()).unwrap(),
+                &text[..cut]
+            );
+            assert_eq!(
+                String::from_utf8(bpe.decode_bytes(&tail).unwrap()).unwrap(),
+                &text[start..]
+            );
+        }
+
+        // "日" is split across three tokens, which are kept or dropped together
+        let i = offsets
+            .iter()
+            .position(|o| o.0 == text.find('日').unwrap())
+            .unwrap();
+        let (head, cut) = bpe.encode_truncated(text, i + 2, &allowed_special);
+        assert_eq!(head.len(), i);
+        assert_eq!(&text[cut..cut + 3], "日");
+
+        let (head, tail, dropped) = bpe.encode_truncated_middle(text, 100, &allowed_special);
+        assert_eq!(head, tokens);
+        assert!(tail.is_empty());
+        assert_eq!(dropped, (text.len(), text.len()));
+    }
+
+    #[test]
+    fn test_core_bpe_from_merges() {
+        let bpe = setup_core_bpe();
+        let text = "the world, héllo wörld!\n\n  abc";
+        let merges = derive_merges(&bpe.encoder).merges;
+
+        // Shuffle the token values, which doesn't change anything when merges are explicit
+        let encoder: HashMap<Vec<u8>, Rank> = bpe
+            .encoder
+            .iter()
+            .map(|(token, rank)| (token.clone(), 5000 - rank))
+            .collect();
+        let shuffled = CoreBPE::from_merges(encoder.clone(), merges, [], CL100K_PATTERN).unwrap();
+        let tokens = shuffled.encode_ordinary(text);
+        assert_eq!(shuffled.decode_bytes(&tokens).unwrap(), text.as_bytes());
+        let expected: Vec<Rank> = bpe
+            .encode_ordinary(text)
+            .iter()
+            .map(|token| encoder[&bpe.decoder[token]])
+            .collect();
+        assert_eq!(tokens, expected);
+        assert_eq!(shuffled.count_tokens_ordinary(text), tokens.len());
+
+        // No merge makes "hello", since "ll" gets merged before "hel" can be
+        assert_eq!(bpe.encode_ordinary("hello").len(), 1);
+        assert_eq!(shuffled.encode_ordinary("hello").len(), 3);
+    }
 }
@@ -0,0 +1,306 @@
+//! A backtracking-free BPE encoder that runs in worst case linear time in the length of the piece.
+//!
+//! This relies on a property of BPE: a sequence of tokens is the BPE encoding of its bytes if and
+//! only if every pair of adjacent tokens is "compatible", i.e. `byte_pair_encode` of the two
+//! tokens' bytes gives back exactly those two tokens. So we can walk the piece from left to right
+//! and, for every prefix, pick the unique token ending there that is compatible with the last
+//! token of the encoding of the prefix before it. An Aho-Corasick automaton gives us all tokens
+//! ending at a position, and since there are at most "longest token" of those, the whole thing is
+//! linear. See https://github.com/github/rust-gems/tree/main/crates/bpe for a longer write-up.
+//!
+//! Internally, tokens are identified by their index when sorted by rank. That way comparing two
+//! ids compares merge priority, even if the ranks of the vocabulary have gaps.
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{_byte_pair_merge, BuildError, Rank};
+
+pub struct LinearEncoder {
+    /// Only contains tokens that `byte_pair_encode` can actually produce
+    automaton: AhoCorasick,
+    /// Maps automaton pattern ids to token ids
+    pattern_tokens: Vec<u32>,
+    token_ranks: Vec<Rank>,
+    token_lens: Vec<usize>,
+    /// The last merge BPE does to build each token. Single bytes (and tokens BPE never produces)
+    /// split into themselves.
+    split_table: Vec<(u32, u32)>,
+    pair_lookup: HashMap<(u32, u32), u32>,
+}
+
+impl LinearEncoder {
+    pub fn new(encoder: &HashMap<Vec<u8>, Rank>) -> Result<Self, BuildError> {
+        let mut tokens: Vec<(&[u8], Rank)> =
+            encoder.iter().map(|(k, v)| (k.as_slice(), *v)).collect();
+        tokens.sort_by_key(|&(_, rank)| rank);
+        let ids: HashMap<&[u8], u32> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, &(bytes, _))| (bytes, id as u32))
+            .collect();
+
+        let mut split_table = Vec::with_capacity(tokens.len());
+        let mut pair_lookup = HashMap::default();
+        let mut reachable = vec![false; tokens.len()];
+        for (id, &(bytes, _)) in tokens.iter().enumerate() {
+            let id = id as u32;
+            if bytes.len() < 2 || _byte_pair_merge(encoder, bytes).len() != 2 {
+                // Either a single byte, or a token that BPE never produces (its bytes always get
+                // encoded as something else), which therefore must not show up in our encodings
+                reachable[id as usize] = bytes.len() == 1;
+                split_table.push((id, id));
+                continue;
+            }
+            // Find the split of this token into two lower ranked tokens that BPE would produce
+            // if this token didn't exist. The final merge BPE does is exactly that split.
+            let split = (1..bytes.len()).find_map(|i| {
+                let token1 = *ids.get(&bytes[..i])?;
+                let token2 = *ids.get(&bytes[i..])?;
+                let valid = token1 < id
+                    && token2 < id
+                    && reachable[token1 as usize]
+                    && reachable[token2 as usize]
+                    && is_valid_token_pair(&pair_lookup, &split_table, token1, token2);
+                valid.then_some((token1, token2))
+            });
+            match split {
+                Some(split) => {
+                    pair_lookup.insert(split, id);
+                    split_table.push(split);
+                    reachable[id as usize] = true;
+                }
+                None => {
+                    return Err(BuildError::UnsupportedVocab(format!(
+                        "token {:?} (rank {}) is not a merge of two lower ranked tokens",
+                        String::from_utf8_lossy(bytes),
+                        tokens[id as usize].1
+                    )))
+                }
+            }
+        }
+
+        let pattern_tokens: Vec<u32> = (0..tokens.len() as u32)
+            .filter(|&id| reachable[id as usize])
+            .collect();
+        let automaton = AhoCorasick::builder()
+            .match_kind(MatchKind::Standard)
+            .build(pattern_tokens.iter().map(|&id| tokens[id as usize].0))
+            .map_err(|e| BuildError::UnsupportedVocab(e.to_string()))?;
+
+        Ok(LinearEncoder {
+            automaton,
+            pattern_tokens,
+            token_ranks: tokens.iter().map(|&(_, rank)| rank).collect(),
+            token_lens: tokens.iter().map(|&(bytes, _)| bytes.len()).collect(),
+            split_table,
+            pair_lookup,
+        })
+    }
+
+    /// Gives the same result as `byte_pair_encode(piece, ranks)`.
+    pub fn encode(&self, piece: &[u8]) -> Vec<Rank> {
+        let last_token = self._last_tokens(piece);
+        let mut ret = vec![];
+        let mut end = piece.len();
+        while end > 0 {
+            let token = last_token[end - 1];
+            ret.push(self.token_ranks[token as usize]);
+            end -= self.token_lens[token as usize];
+        }
+        ret.reverse();
+        ret
+    }
+
+    /// Gives the same result as `encode(piece).len()`.
+    pub fn count(&self, piece: &[u8]) -> usize {
+        let last_token = self._last_tokens(piece);
+        let mut count = 0;
+        let mut end = piece.len();
+        while end > 0 {
+            count += 1;
+            end -= self.token_lens[last_token[end - 1] as usize];
+        }
+        count
+    }
+
+    /// Returns, for every i, the last token of the encoding of piece[..i + 1].
+    fn _last_tokens(&self, piece: &[u8]) -> Vec<u32> {
+        let mut last_token = vec![u32::MAX; piece.len()];
+        // Matches are reported in order of their end position
+        for m in self.automaton.find_overlapping_iter(piece) {
+            if last_token[m.end() - 1] != u32::MAX {
+                continue;
+            }
+            let token = self.pattern_tokens[m.pattern().as_usize()];
+            if m.start() == 0
+                || is_valid_token_pair(
+                    &self.pair_lookup,
+                    &self.split_table,
+                    last_token[m.start() - 1],
+                    token,
+                )
+            {
+                last_token[m.end() - 1] = token;
+            }
+        }
+        if let Some(i) = last_token.iter().position(|&token| token == u32::MAX) {
+            panic!("byte {} of the piece is not in the vocabulary", i);
+        }
+        last_token
+    }
+}
+
+/// Checks whether `byte_pair_encode` of the bytes of `token1` followed by the bytes of `token2`
+/// gives back `[token1, token2]`. We undo the merges that built the two tokens, most recent first,
+/// and check that at no point BPE would have preferred a merge across the boundary instead.
+fn is_valid_token_pair(
+    pair_lookup: &HashMap<(u32, u32), u32>,
+    split_table: &[(u32, u32)],
+    mut token1: u32,
+    mut token2: u32,
+) -> bool {
+    // The merge we last undid. A merge across the boundary is only a problem if BPE would have
+    // done it before that one. Ties go to the leftmost pair, hence the + 1 when we undo a merge on
+    // the right hand side.
+    let mut limit = u32::MAX;
+    loop {
+        if let Some(&combined) = pair_lookup.get(&(token1, token2)) {
+            if combined < limit {
+                return false;
+            }
+        }
+        if token1 > token2 {
+            limit = token1;
+            token1 = split_table[token1 as usize].1;
+            if token1 == limit {
+                limit = token2 + 1;
+                token2 = split_table[token2 as usize].0;
+                if token2 + 1 == limit {
+                    return true;
+                }
+            }
+        } else {
+            limit = token2 + 1;
+            token2 = split_table[token2 as usize].0;
+            if token2 + 1 == limit {
+                limit = token1;
+                token1 = split_table[token1 as usize].1;
+                if token1 == limit {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::LinearEncoder;
+    use crate::tests::{setup_encoder, Lcg};
+    use crate::{byte_pair_encode, Rank};
+
+    /// Trains a small vocabulary the slow and obvious way, so that ranks are merge priorities.
+    fn train_ranks(corpus: &[u8], vocab_size: usize) -> HashMap<Vec<u8>, Rank> {
+        let mut ranks = setup_encoder(&[]);
+        let mut words: Vec<Vec<Vec<u8>>> = corpus
+            .split(|&b| b == b' ')
+            .map(|w| w.iter().map(|&b| vec![b]).collect())
+            .collect();
+        while ranks.len() < vocab_size {
+            let mut counts: HashMap<(Vec<u8>, Vec<u8>), usize> = HashMap::default();
+            for word in &words {
+                for pair in word.windows(2) {
+                    *counts
+                        .entry((pair[0].clone(), pair[1].clone()))
+                        .or_default() += 1;
+                }
+            }
+            let best = match counts
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            {
+                Some((pair, _)) => pair,
+                None => break,
+            };
+            let merged = [best.0.as_slice(), best.1.as_slice()].concat();
+            for word in &mut words {
+                let mut i = 0;
+                while i + 1 < word.len() {
+                    if word[i] == best.0 && word[i + 1] == best.1 {
+                        word[i] = merged.clone();
+                        word.remove(i + 1);
+                    }
+                    i += 1;
+                }
+            }
+            let rank = ranks.len() as Rank;
+            ranks.insert(merged, rank);
+        }
+        ranks
+    }
+
+    fn random_text(rng: &mut Lcg, alphabet: &[u8], len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| alphabet[rng.next() % alphabet.len()])
+            .collect()
+    }
+
+    #[test]
+    fn test_linear_matches_byte_pair_encode() {
+        let mut rng = Lcg(1);
+        let alphabet = b"aaabbc ";
+        let corpus = random_text(&mut rng, alphabet, 5000);
+        let ranks = train_ranks(&corpus, 400);
+        let linear = LinearEncoder::new(&ranks).unwrap();
+
+        for len in [2, 3, 5, 10, 30, 100, 200] {
+            for _ in 0..50 {
+                let piece = random_text(&mut rng, b"aaabbc", len);
+                let expected = byte_pair_encode(&piece, &ranks);
+                assert_eq!(
+                    linear.encode(&piece),
+                    expected,
+                    "{:?}",
+                    String::from_utf8_lossy(&piece)
+                );
+                assert_eq!(linear.count(&piece), expected.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_linear_adversarial() {
+        let mut rng = Lcg(2);
+        let corpus = [
+            b"a".repeat(300),
+            b"ab".repeat(100),
+            random_text(&mut rng, b"ab", 300),
+        ]
+        .join(&b' ');
+        let ranks = train_ranks(&corpus, 300);
+        let linear = LinearEncoder::new(&ranks).unwrap();
+        for piece in [b"a".repeat(2000), b"ab".repeat(1000), b"aab".repeat(700)] {
+            assert_eq!(linear.encode(&piece), byte_pair_encode(&piece, &ranks));
+        }
+    }
+
+    #[test]
+    fn test_linear_unreachable_token() {
+        // "abcd" always gets stuck at "ab" + "c" + "d", so this token is never produced
+        let ranks = setup_encoder(&[b"ab", b"bc", b"abcd"]);
+        let linear = LinearEncoder::new(&ranks).unwrap();
+        for piece in [&b"abcd"[..], b"abcdabcd", b"bcdabcd", b"aabcdd"] {
+            assert_eq!(linear.encode(piece), byte_pair_encode(piece, &ranks));
+        }
+    }
+
+    #[test]
+    fn test_linear_unsupported_vocab() {
+        // "abc" is built from "ab", which has a higher rank
+        let ranks = setup_encoder(&[b"abc", b"ab"]);
+        assert!(LinearEncoder::new(&ranks).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+//! Loading of `.tiktoken` rank files, the Rust equivalent of `tiktoken/load.py`.
+//!
+//! A `.tiktoken` file has one `<base64 token bytes> <rank>` pair per line. GPT-2 style
+//! encodings instead ship as a data gym `vocab.bpe` merges file plus an `encoder.json`.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+use base64::Engine;
+use rustc_hash::FxHashMap as HashMap;
+use sha2::{Digest, Sha256};
+
+use crate::{BuildError, CoreBPE, Rank};
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// `line` is 1-indexed, to match what you'd see in an editor.
+    InvalidLine {
+        line: usize,
+        reason: String,
+    },
+    Json(serde_json::Error),
+    /// The data gym files are malformed or don't agree with each other.
+    DataGym(String),
+    /// The `tokenizer.json` is malformed or uses features tiktoken can't represent.
+    HuggingFace(String),
+    Build(BuildError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::HashMismatch { expected, actual } => write!(
+                f,
+                "Hash mismatch (expected {}, got {}). This may indicate a corrupted file.",
+                expected, actual
+            ),
+            LoadError::InvalidLine { line, reason } => write!(f, "Line {}: {}", line, reason),
+            LoadError::Json(e) => write!(f, "{}", e),
+            LoadError::DataGym(reason) => write!(f, "{}", reason),
+            LoadError::HuggingFace(reason) => write!(f, "{}", reason),
+            LoadError::Build(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(e: serde_json::Error) -> Self {
+        LoadError::Json(e)
+    }
+}
+
+impl From<BuildError> for LoadError {
+    fn from(e: BuildError) -> Self {
+        LoadError::Build(e)
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn check_hash(data: &[u8], expected_hash: &str) -> Result<(), LoadError> {
+    let actual = sha256_hex(data);
+    if actual.eq_ignore_ascii_case(expected_hash) {
+        Ok(())
+    } else {
+        Err(LoadError::HashMismatch {
+            expected: expected_hash.to_string(),
+            actual,
+        })
+    }
+}
+
+pub fn load_tiktoken_bpe(
+    contents: &[u8],
+    expected_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    if let Some(expected_hash) = expected_hash {
+        check_hash(contents, expected_hash)?;
+    }
+
+    let mut ranks = HashMap::default();
+    for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+        let invalid = |reason: &str| LoadError::InvalidLine {
+            line: i + 1,
+            reason: reason.to_string(),
+        };
+        let line = std::str::from_utf8(line).map_err(|_| invalid("not valid UTF-8"))?;
+        let mut parts = line.split_ascii_whitespace();
+        let (token, rank) = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => continue,
+            (Some(token), Some(rank), None) => (token, rank),
+            _ => return Err(invalid("expected `<base64 token> <rank>`")),
+        };
+        let token = base64::engine::general_purpose::STANDARD
+            .decode(token)
+            .map_err(|e| invalid(&e.to_string()))?;
+        let rank: Rank = rank.parse().map_err(|_| invalid("invalid rank"))?;
+        ranks.insert(token, rank);
+    }
+    Ok(ranks)
+}
+
+pub fn load_tiktoken_bpe_file<P: AsRef<Path>>(
+    path: P,
+    expected_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    let contents = std::fs::read(path)?;
+    load_tiktoken_bpe(&contents, expected_hash)
+}
+
+/// The order in which data gym assigns ranks to single bytes, i.e. GPT-2's `bytes_to_unicode`.
+/// Printable bytes (other than space) come first and represent themselves; the remaining bytes
+/// are represented by the characters starting at U+0100.
+pub(crate) fn data_gym_byte_order() -> (Vec<u8>, HashMap<char, u8>) {
+    let is_printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let mut rank_to_intbyte: Vec<u8> = (0..=255u8).filter(|&b| is_printable(b)).collect();
+    let mut data_gym_byte_to_byte: HashMap<char, u8> = rank_to_intbyte
+        .iter()
+        .map(|&b| (char::from(b), b))
+        .collect();
+    let mut n = 0;
+    for b in 0..=255u8 {
+        if !is_printable(b) {
+            rank_to_intbyte.push(b);
+            data_gym_byte_to_byte.insert(char::from_u32(256 + n).unwrap(), b);
+            n += 1;
+        }
+    }
+    assert_eq!(rank_to_intbyte.len(), 256);
+    (rank_to_intbyte, data_gym_byte_to_byte)
+}
+
+/// How data gym writes each byte, the reverse of the map from `data_gym_byte_order`. For example
+/// a space is written as "Ġ".
+pub(crate) fn data_gym_byte_to_char() -> Vec<char> {
+    let (_, data_gym_byte_to_byte) = data_gym_byte_order();
+    let mut byte_to_char = vec!['\0'; 256];
+    for (c, b) in data_gym_byte_to_byte {
+        byte_to_char[b as usize] = c;
+    }
+    byte_to_char
+}
+
+/// Writes merges in the format of data gym's `vocab.bpe`, also known as `merges.txt`: a version
+/// header, then one merge per line with both halves in data gym's byte encoding.
+pub fn write_merges_txt<W: Write>(merges: &[(Vec<u8>, Vec<u8>)], mut writer: W) -> io::Result<()> {
+    let byte_to_char = data_gym_byte_to_char();
+    let encode =
+        |token: &[u8]| -> String { token.iter().map(|&b| byte_to_char[b as usize]).collect() };
+    writeln!(writer, "#version: 0.2")?;
+    for (left, right) in merges {
+        writeln!(writer, "{} {}", encode(left), encode(right))?;
+    }
+    Ok(())
+}
+
+pub fn data_gym_to_mergeable_bpe_ranks(
+    vocab_bpe: &[u8],
+    encoder_json: &[u8],
+    vocab_bpe_hash: Option<&str>,
+    encoder_json_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    if let Some(expected_hash) = vocab_bpe_hash {
+        check_hash(vocab_bpe, expected_hash)?;
+    }
+    if let Some(expected_hash) = encoder_json_hash {
+        check_hash(encoder_json, expected_hash)?;
+    }
+
+    let (rank_to_intbyte, data_gym_byte_to_byte) = data_gym_byte_order();
+    let decode_data_gym = |value: &str| -> Result<Vec<u8>, LoadError> {
+        value
+            .chars()
+            .map(|c| {
+                data_gym_byte_to_byte.get(&c).copied().ok_or_else(|| {
+                    LoadError::DataGym(format!("Unexpected character {:?} in {:?}", c, value))
+                })
+            })
+            .collect()
+    };
+
+    // add the single byte tokens
+    let mut bpe_ranks: HashMap<Vec<u8>, Rank> = rank_to_intbyte
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| (vec![b], i as Rank))
+        .collect();
+
+    // vocab_bpe contains the merges along with associated ranks. The first line is a version
+    // header and the file ends with a trailing newline.
+    let vocab_bpe = std::str::from_utf8(vocab_bpe)
+        .map_err(|_| LoadError::DataGym("vocab.bpe is not valid UTF-8".to_string()))?;
+    let lines: Vec<&str> = vocab_bpe.split('\n').collect();
+    let n = bpe_ranks.len();
+    for (i, merge_str) in lines
+        .iter()
+        .enumerate()
+        .take(lines.len().saturating_sub(1))
+        .skip(1)
+    {
+        let mut parts = merge_str.split_whitespace();
+        let (first, second) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(first), Some(second), None) => (first, second),
+            _ => {
+                return Err(LoadError::InvalidLine {
+                    line: i + 1,
+                    reason: "expected `<first> <second>`".to_string(),
+                })
+            }
+        };
+        let mut merged = decode_data_gym(first)?;
+        merged.extend(decode_data_gym(second)?);
+        // i starts at 1 because of the header line
+        bpe_ranks.insert(merged, (n + i - 1) as Rank);
+    }
+
+    // check that the encoder file matches the merges file
+    // this sanity check is important since tiktoken assumes that ranks are ordered the same
+    // as merge priority
+    let encoder_json: HashMap<String, Rank> = serde_json::from_slice(encoder_json)?;
+    let mut encoder_json_loaded: HashMap<Vec<u8>, Rank> = HashMap::default();
+    for (k, v) in encoder_json {
+        // drop these two special tokens if present, since they're not mergeable bpe tokens
+        if k == "<|endoftext|>" || k == "<|startoftext|>" {
+            continue;
+        }
+        encoder_json_loaded.insert(decode_data_gym(&k)?, v);
+    }
+    if bpe_ranks != encoder_json_loaded {
+        return Err(LoadError::DataGym(format!(
+            "encoder.json ({} tokens) does not match vocab.bpe ({} tokens)",
+            encoder_json_loaded.len(),
+            bpe_ranks.len()
+        )));
+    }
+
+    Ok(bpe_ranks)
+}
+
+pub fn data_gym_to_mergeable_bpe_ranks_files<P: AsRef<Path>, Q: AsRef<Path>>(
+    vocab_bpe_file: P,
+    encoder_json_file: Q,
+    vocab_bpe_hash: Option<&str>,
+    encoder_json_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    let vocab_bpe = std::fs::read(vocab_bpe_file)?;
+    let encoder_json = std::fs::read(encoder_json_file)?;
+    data_gym_to_mergeable_bpe_ranks(&vocab_bpe, &encoder_json, vocab_bpe_hash, encoder_json_hash)
+}
+
+impl CoreBPE {
+    pub fn from_tiktoken_bpe<SE>(
+        contents: &[u8],
+        expected_hash: Option<&str>,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, LoadError>
+    where
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder = load_tiktoken_bpe(contents, expected_hash)?;
+        Ok(CoreBPE::new(encoder, special_tokens_encoder, pattern)?)
+    }
+
+    pub fn from_tiktoken_bpe_file<P, SE>(
+        path: P,
+        expected_hash: Option<&str>,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, LoadError>
+    where
+        P: AsRef<Path>,
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder = load_tiktoken_bpe_file(path, expected_hash)?;
+        Ok(CoreBPE::new(encoder, special_tokens_encoder, pattern)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        data_gym_byte_order, data_gym_to_mergeable_bpe_ranks, load_tiktoken_bpe, sha256_hex,
+        write_merges_txt, LoadError,
+    };
+
+    const CONTENTS: &[u8] = b"IQ== 0\nIg== 1\naGVsbG8= 2\n\n";
+
+    #[test]
+    fn test_load_tiktoken_bpe() {
+        let ranks = load_tiktoken_bpe(CONTENTS, None).unwrap();
+        assert_eq!(ranks.len(), 3);
+        assert_eq!(ranks[&b"!".to_vec()], 0);
+        assert_eq!(ranks[&b"\"".to_vec()], 1);
+        assert_eq!(ranks[&b"hello".to_vec()], 2);
+    }
+
+    #[test]
+    fn test_load_tiktoken_bpe_hash() {
+        let hash = sha256_hex(CONTENTS);
+        assert!(load_tiktoken_bpe(CONTENTS, Some(&hash)).is_ok());
+        assert!(matches!(
+            load_tiktoken_bpe(CONTENTS, Some(&sha256_hex(b"other"))),
+            Err(LoadError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load_tiktoken_bpe_invalid_line() {
+        assert!(matches!(
+            load_tiktoken_bpe(b"IQ== 0\nIg==\n", None),
+            Err(LoadError::InvalidLine { line: 2, .. })
+        ));
+        assert!(matches!(
+            load_tiktoken_bpe(b"IQ== zero\n", None),
+            Err(LoadError::InvalidLine { line: 1, .. })
+        ));
+    }
+
+    fn data_gym_encoder_json(extra: &[(&str, u32)]) -> Vec<u8> {
+        let (rank_to_intbyte, data_gym_byte_to_byte) = data_gym_byte_order();
+        let byte_to_char: std::collections::HashMap<u8, char> = data_gym_byte_to_byte
+            .iter()
+            .map(|(&c, &b)| (b, c))
+            .collect();
+        let mut entries: Vec<String> = rank_to_intbyte
+            .iter()
+            .enumerate()
+            .map(|(i, b)| format!("{}: {}", serde_json::json!(byte_to_char[b].to_string()), i))
+            .collect();
+        entries.extend(
+            extra
+                .iter()
+                .map(|(k, v)| format!("{}: {}", serde_json::json!(k), v)),
+        );
+        format!("{{{}}}", entries.join(", ")).into_bytes()
+    }
+
+    #[test]
+    fn test_data_gym_byte_order() {
+        let (rank_to_intbyte, data_gym_byte_to_byte) = data_gym_byte_order();
+        assert_eq!(rank_to_intbyte[0], b'!');
+        // the first non-printable byte is \x00, mapped to U+0100
+        assert_eq!(rank_to_intbyte[188], 0);
+        assert_eq!(data_gym_byte_to_byte[&'\u{100}'], 0);
+        // space is famously rendered as Ġ
+        assert_eq!(data_gym_byte_to_byte[&'Ġ'], b' ');
+    }
+
+    #[test]
+    fn test_write_merges_txt() {
+        let merges = vec![
+            (b" ".to_vec(), b"t".to_vec()),
+            (b" t".to_vec(), b"he".to_vec()),
+        ];
+        let mut merges_txt = vec![];
+        write_merges_txt(&merges, &mut merges_txt).unwrap();
+        // The same file as in test_data_gym_to_mergeable_bpe_ranks
+        assert_eq!(merges_txt, "#version: 0.2\nĠ t\nĠt he\n".as_bytes());
+    }
+
+    #[test]
+    fn test_data_gym_to_mergeable_bpe_ranks() {
+        let vocab_bpe = "#version: 0.2\nĠ t\nĠt he\n".as_bytes();
+        let encoder_json =
+            data_gym_encoder_json(&[("Ġt", 256), ("Ġthe", 257), ("<|endoftext|>", 258)]);
+        let ranks = data_gym_to_mergeable_bpe_ranks(vocab_bpe, &encoder_json, None, None).unwrap();
+        assert_eq!(ranks.len(), 258);
+        assert_eq!(ranks[&b" t".to_vec()], 256);
+        assert_eq!(ranks[&b" the".to_vec()], 257);
+
+        let mismatched = data_gym_encoder_json(&[("Ġt", 257), ("Ġthe", 256)]);
+        assert!(matches!(
+            data_gym_to_mergeable_bpe_ranks(vocab_bpe, &mismatched, None, None),
+            Err(LoadError::DataGym(_))
+        ));
+    }
+}
@@ -0,0 +1,339 @@
+//! BPE driven by an explicit, ordered list of merges, for vocabularies where token values don't
+//! follow merge priority (e.g. Hugging Face `vocab.json` plus `merges.txt`).
+//!
+//! `byte_pair_encode` looks up the rank of the bytes a merge would produce, which only works if
+//! ranks are merge priorities. Here we instead look up the priority of the pair of tokens.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{byte_pair_split, BuildError, Rank};
+
+pub struct MergeList {
+    /// The merges in priority order, as pairs of token values
+    merges: Vec<(Rank, Rank)>,
+    /// Maps a pair of token values to the index of its merge and the token it merges into
+    pair_lookup: HashMap<(Rank, Rank), (usize, Rank)>,
+    byte_tokens: Vec<Rank>,
+    /// Whether a piece that is in the vocabulary is encoded as that token without applying
+    /// merges, like Hugging Face's `ignore_merges`
+    pub(crate) ignore_merges: bool,
+}
+
+impl MergeList {
+    /// `merges` are pairs of token bytes, highest priority first. Both halves of a merge and
+    /// their concatenation have to be in `encoder`, as do all single bytes. If a pair is listed
+    /// more than once, the first one counts.
+    pub fn new<M>(encoder: &HashMap<Vec<u8>, Rank>, merges: M) -> Result<Self, BuildError>
+    where
+        M: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let byte_tokens = (0..=255u8)
+            .map(|b| {
+                encoder.get(&[b][..]).copied().ok_or_else(|| {
+                    BuildError::UnsupportedVocab(format!(
+                        "the vocabulary is missing the single byte {:#04x}",
+                        b
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut ret = MergeList {
+            merges: vec![],
+            pair_lookup: HashMap::default(),
+            byte_tokens,
+            ignore_merges: false,
+        };
+        for (i, (left, right)) in merges.into_iter().enumerate() {
+            let lookup = |bytes: &[u8]| {
+                encoder.get(bytes).copied().ok_or_else(|| {
+                    BuildError::UnsupportedVocab(format!(
+                        "merge {} ({:?} + {:?}) uses {:?}, which is not in the vocabulary",
+                        i,
+                        String::from_utf8_lossy(&left),
+                        String::from_utf8_lossy(&right),
+                        String::from_utf8_lossy(bytes)
+                    ))
+                })
+            };
+            let pair = (lookup(&left)?, lookup(&right)?);
+            let merged = lookup(&[left.as_slice(), right.as_slice()].concat())?;
+            if !ret.pair_lookup.contains_key(&pair) {
+                ret.pair_lookup.insert(pair, (ret.merges.len(), merged));
+                ret.merges.push(pair);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The merges in priority order, as pairs of token values.
+    pub fn merges(&self) -> &[(Rank, Rank)] {
+        &self.merges
+    }
+
+    pub fn ignore_merges(&self) -> bool {
+        self.ignore_merges
+    }
+
+    /// Starts from single bytes and repeatedly applies the highest priority merge, leftmost
+    /// first, until none applies.
+    pub fn encode(&self, piece: &[u8]) -> Vec<Rank> {
+        let (tokens, next, num_parts) = self._merge(piece);
+        let mut ret = Vec::with_capacity(num_parts);
+        let mut i = 0;
+        while i < piece.len() {
+            ret.push(tokens[i]);
+            i = next[i];
+        }
+        ret
+    }
+
+    /// Gives the same result as `encode(piece).len()`.
+    pub fn count(&self, piece: &[u8]) -> usize {
+        self._merge(piece).2
+    }
+
+    /// Returns the token starting at each part, the start of the next part, and how many parts
+    /// there are. Entries for positions that aren't the start of a part are garbage.
+    fn _merge(&self, piece: &[u8]) -> (Vec<Rank>, Vec<usize>, usize) {
+        // Same idea as `_byte_pair_merge_large`: a linked list of parts and a heap of merges.
+        // Entries are stale if their part has been merged away or now starts a different pair.
+        let n = piece.len();
+        let mut tokens: Vec<Rank> = piece
+            .iter()
+            .map(|&b| self.byte_tokens[b as usize])
+            .collect();
+        let mut next: Vec<usize> = (1..=n).collect();
+        let mut prev: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect();
+        let mut alive = vec![true; n];
+        let mut num_parts = n;
+
+        let mut heap = BinaryHeap::with_capacity(n);
+        for i in 0..n.saturating_sub(1) {
+            if let Some(&(priority, _)) = self.pair_lookup.get(&(tokens[i], tokens[i + 1])) {
+                heap.push(Reverse((priority, i)));
+            }
+        }
+
+        while let Some(Reverse((priority, i))) = heap.pop() {
+            if !alive[i] || next[i] >= n {
+                continue;
+            }
+            let j = next[i];
+            let merged = match self.pair_lookup.get(&(tokens[i], tokens[j])) {
+                Some(&(p, merged)) if p == priority => merged,
+                _ => continue,
+            };
+            tokens[i] = merged;
+            alive[j] = false;
+            num_parts -= 1;
+            next[i] = next[j];
+            if next[j] < n {
+                prev[next[j]] = i;
+            }
+
+            if i > 0 {
+                let before = prev[i];
+                if let Some(&(p, _)) = self.pair_lookup.get(&(tokens[before], tokens[i])) {
+                    heap.push(Reverse((p, before)));
+                }
+            }
+            if next[i] < n {
+                if let Some(&(p, _)) = self.pair_lookup.get(&(tokens[i], tokens[next[i]])) {
+                    heap.push(Reverse((p, i)));
+                }
+            }
+        }
+
+        (tokens, next, num_parts)
+    }
+}
+
+/// The merge that made each token of a rank table, which `.tiktoken` files don't record.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DerivedMerges {
+    /// In rank order, one for each token that could be derived
+    pub merges: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Tokens of more than one byte that `byte_pair_encode` can't produce from lower-ranked
+    /// tokens, in rank order. For example tokens that were added to the vocabulary by hand.
+    pub underivable: Vec<Vec<u8>>,
+}
+
+/// Rebuilds the merges of a rank table: each token is the last merge `byte_pair_encode` makes
+/// when encoding it with only the tokens ranked below it. That's the merge that added the token
+/// during training, if the ranks came from one.
+pub fn derive_merges(encoder: &HashMap<Vec<u8>, Rank>) -> DerivedMerges {
+    let mut tokens: Vec<(&Vec<u8>, Rank)> = encoder.iter().map(|(k, &v)| (k, v)).collect();
+    tokens.sort_unstable_by_key(|&(_, rank)| rank);
+
+    let mut ret = DerivedMerges::default();
+    let mut lower_ranked: HashMap<Vec<u8>, Rank> = HashMap::default();
+    for (token, rank) in tokens {
+        if token.len() > 1 {
+            match byte_pair_split(token, &lower_ranked)[..] {
+                [left, right] => ret.merges.push((left.to_vec(), right.to_vec())),
+                _ => ret.underivable.push(token.clone()),
+            }
+        }
+        lower_ranked.insert(token.clone(), rank);
+    }
+    ret
+}
+
+/// Merges that make `MergeList` encode like `byte_pair_encode`, in priority order, as long as
+/// whole pieces that are a token are looked up first. That's one merge per token, the one
+/// `derive_merges` finds, in rank order. Listing every way of splitting a token isn't the same:
+/// `MergeList` picks the pair with the highest priority, while `byte_pair_encode` picks the
+/// leftmost pair that makes the lowest ranked token. From parts "ab", "a", "ba", one merges
+/// "a" + "ba" where the other merges "ab" + "a".
+///
+/// Underivable tokens get every split into two tokens instead, since `byte_pair_encode` can only
+/// reach them by merging parts that came about in the rest of a piece.
+pub(crate) fn merges_from_ranks(encoder: &HashMap<Vec<u8>, Rank>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut tokens: Vec<(&Vec<u8>, Rank)> = encoder.iter().map(|(k, &v)| (k, v)).collect();
+    tokens.sort_unstable_by_key(|&(_, rank)| rank);
+
+    let mut merges = vec![];
+    let mut lower_ranked: HashMap<Vec<u8>, Rank> = HashMap::default();
+    for (token, rank) in tokens {
+        if token.len() > 1 {
+            match byte_pair_split(token, &lower_ranked)[..] {
+                [left, right] => merges.push((left.to_vec(), right.to_vec())),
+                _ => {
+                    for i in 1..token.len() {
+                        let (left, right) = token.split_at(i);
+                        if encoder.contains_key(left) && encoder.contains_key(right) {
+                            merges.push((left.to_vec(), right.to_vec()));
+                        }
+                    }
+                }
+            }
+        }
+        lower_ranked.insert(token.clone(), rank);
+    }
+    merges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_merges, merges_from_ranks, DerivedMerges, MergeList};
+    use crate::tests::setup_encoder;
+    use crate::{byte_pair_encode, Rank};
+
+    fn merges(merges: &[(&[u8], &[u8])]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        merges
+            .iter()
+            .map(|(left, right)| (left.to_vec(), right.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_list_matches_ranks() {
+        // When merge order and ranks agree, this is just byte_pair_encode
+        let encoder = setup_encoder(&[b"ab", b"cd", b"abcd", b"aab"]);
+        let merge_list = MergeList::new(
+            &encoder,
+            merges(&[(b"a", b"b"), (b"c", b"d"), (b"ab", b"cd"), (b"a", b"ab")]),
+        )
+        .unwrap();
+        for piece in [
+            &b"abcd"[..],
+            b"aabcd",
+            b"abababcdcd",
+            b"aaabbbcccddd",
+            b"xy",
+        ] {
+            let expected = byte_pair_encode(piece, &encoder);
+            assert_eq!(merge_list.count(piece), expected.len());
+            assert_eq!(merge_list.encode(piece), expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_list_priority() {
+        // Token values are the reverse of merge priority, so "bc" is merged before "ab"
+        let encoder = setup_encoder(&[b"ab", b"bc"]);
+        let merge_list = MergeList::new(&encoder, merges(&[(b"b", b"c"), (b"a", b"b")])).unwrap();
+        assert_eq!(
+            merge_list.encode(b"abc"),
+            vec![b'a' as Rank, encoder[&b"bc"[..]]]
+        );
+        assert_eq!(
+            byte_pair_encode(b"abc", &encoder),
+            vec![encoder[&b"ab"[..]], b'c' as Rank]
+        );
+
+        // "ab" is in the vocabulary, but there is no merge that makes it
+        let merge_list = MergeList::new(&encoder, merges(&[(b"b", b"c")])).unwrap();
+        assert_eq!(merge_list.encode(b"ab"), vec![b'a' as Rank, b'b' as Rank]);
+        assert_eq!(merge_list.count(b"ab"), 2);
+    }
+
+    #[test]
+    fn test_merges_from_ranks() {
+        // "abc" can come from "a" + "bc" or "ab" + "c", but only the derived merge is listed
+        let mut encoder = setup_encoder(&[b"ab", b"bc", b"abc", b"cd", b"abcd"]);
+        // Underivable, so every split is listed
+        encoder.insert(b"cab".to_vec(), 1000);
+        let merges = merges_from_ranks(&encoder);
+        assert_eq!(
+            merges,
+            vec![
+                (b"a".to_vec(), b"b".to_vec()),
+                (b"b".to_vec(), b"c".to_vec()),
+                (b"ab".to_vec(), b"c".to_vec()),
+                (b"c".to_vec(), b"d".to_vec()),
+                (b"abc".to_vec(), b"d".to_vec()),
+                (b"c".to_vec(), b"ab".to_vec()),
+            ]
+        );
+        let merge_list = MergeList::new(&encoder, merges).unwrap();
+        for piece in [&b"abcd"[..], b"abcabcd", b"bcabcdcd", b"cdabc", b"dcab"] {
+            assert_eq!(merge_list.encode(piece), byte_pair_encode(piece, &encoder));
+        }
+    }
+
+    #[test]
+    fn test_derive_merges() {
+        let mut encoder = setup_encoder(&[b"ab", b"cd", b"abcd", b"aab", b"abc"]);
+        // Not the result of merging two tokens, and not a merge of lower ranked tokens
+        encoder.insert(b"xyz".to_vec(), 1000);
+        encoder.insert(b"ef".to_vec(), 1001);
+        encoder.insert(b"def".to_vec(), 999);
+        let derived = derive_merges(&encoder);
+        assert_eq!(
+            derived,
+            DerivedMerges {
+                merges: merges(&[
+                    (b"a", b"b"),
+                    (b"c", b"d"),
+                    (b"ab", b"cd"),
+                    (b"a", b"ab"),
+                    (b"ab", b"c"),
+                    (b"e", b"f"),
+                ]),
+                underivable: vec![b"def".to_vec(), b"xyz".to_vec()],
+            }
+        );
+
+        // Derived merges encode like the ranks they came from
+        encoder.remove(&b"def"[..]);
+        encoder.remove(&b"xyz"[..]);
+        let merge_list = MergeList::new(&encoder, derive_merges(&encoder).merges).unwrap();
+        for piece in [&b"abcd"[..], b"aabcd", b"abcabcdef", b"aaabbbcccddd"] {
+            assert_eq!(merge_list.encode(piece), byte_pair_encode(piece, &encoder));
+        }
+    }
+
+    #[test]
+    fn test_merge_list_errors() {
+        let encoder = setup_encoder(&[b"ab"]);
+        assert!(MergeList::new(&encoder, merges(&[(b"a", b"c")])).is_err());
+        let mut encoder = encoder;
+        encoder.remove(&vec![b'z']);
+        assert!(MergeList::new(&encoder, merges(&[])).is_err());
+    }
+}
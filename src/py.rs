@@ -0,0 +1,791 @@
+// PyO3 0.20's macros trip this lint on recent compilers
+#![allow(non_local_definitions)]
+
+use std::collections::HashSet;
+
+use pyo3::exceptions;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList, PyTuple};
+use pyo3::PyResult;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::batch::BatchError;
+use crate::chunk::{ChunkBoundary, ChunkError};
+use crate::huggingface::parse_hf_tokenizer_json;
+use crate::load::{load_tiktoken_bpe, write_merges_txt, LoadError};
+use crate::merges::derive_merges;
+use crate::stream::take_complete_utf8;
+use crate::train::{first_new_rank, BpeTrainer, TrainError};
+use crate::validate::{validate_vocab, VocabError};
+use crate::{
+    byte_offsets_to_char_offsets, CoreBPE, DecodeError, DisallowedSpecialError, Rank,
+    SpecialTokenPolicy, SpecialTokenSet, UnknownTokenPolicy,
+};
+
+impl From<DecodeError> for PyErr {
+    fn from(e: DecodeError) -> Self {
+        PyErr::new::<exceptions::PyKeyError, _>(e.to_string())
+    }
+}
+
+impl From<BatchError> for PyErr {
+    fn from(e: BatchError) -> Self {
+        match e {
+            BatchError::ThreadPool(_) => PyErr::new::<exceptions::PyRuntimeError, _>(e.to_string()),
+            BatchError::Decode(e) => e.into(),
+            BatchError::DisallowedSpecial(e) => {
+                Python::with_gil(|py| disallowed_special_token_error(py, e))
+            }
+        }
+    }
+}
+
+impl From<ChunkError> for PyErr {
+    fn from(e: ChunkError) -> Self {
+        PyErr::new::<exceptions::PyValueError, _>(e.to_string())
+    }
+}
+
+impl From<LoadError> for PyErr {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Io(e) => e.into(),
+            e => PyErr::new::<exceptions::PyValueError, _>(e.to_string()),
+        }
+    }
+}
+
+#[pymethods]
+impl CoreBPE {
+    #[new]
+    #[pyo3(signature = (encoder, special_tokens_encoder, pattern, keep_unmatched = false))]
+    fn py_new(
+        encoder: HashMap<Vec<u8>, Rank>,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str,
+        keep_unmatched: bool,
+    ) -> PyResult<Self> {
+        let mut bpe = Self::new(encoder, special_tokens_encoder, pattern)
+            .map_err(|e| PyErr::new::<exceptions::PyValueError, _>(e.to_string()))?;
+        bpe._set_keep_unmatched(keep_unmatched);
+        Ok(bpe)
+    }
+
+    #[staticmethod]
+    #[pyo3(
+        name = "from_merges",
+        signature = (
+            encoder,
+            merges,
+            special_tokens_encoder,
+            pattern,
+            ignore_merges = false,
+            keep_unmatched = false
+        )
+    )]
+    fn py_from_merges(
+        encoder: HashMap<Vec<u8>, Rank>,
+        merges: Vec<(Vec<u8>, Vec<u8>)>,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str,
+        ignore_merges: bool,
+        keep_unmatched: bool,
+    ) -> PyResult<Self> {
+        let mut bpe = Self::from_merges(encoder, merges, special_tokens_encoder, pattern)
+            .map_err(|e| PyErr::new::<exceptions::PyValueError, _>(e.to_string()))?;
+        bpe._set_ignore_merges(ignore_merges);
+        bpe._set_keep_unmatched(keep_unmatched);
+        Ok(bpe)
+    }
+
+    #[pyo3(name = "with_special_tokens")]
+    fn py_with_special_tokens(
+        &self,
+        py: Python,
+        add: HashMap<String, Rank>,
+        remove: Vec<&str>,
+    ) -> PyResult<Self> {
+        py.allow_threads(|| self.with_special_tokens(add, &remove))
+            .map_err(|e| PyErr::new::<exceptions::PyValueError, _>(e.to_string()))
+    }
+
+    // ====================
+    // Encoding
+    // ====================
+
+    #[pyo3(name = "encode_ordinary")]
+    fn py_encode_ordinary(&self, py: Python, text: &str) -> Vec<Rank> {
+        py.allow_threads(|| self.encode_ordinary(text))
+    }
+
+    #[pyo3(name = "encode")]
+    fn py_encode(&self, py: Python, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
+        py.allow_threads(|| self.encode(text, &allowed_special))
+    }
+
+    /// `allowed_special` and `disallowed_special` are each "all" or a collection of special
+    /// tokens, see `CoreBPE::encode_checked`.
+    #[pyo3(name = "encode_checked")]
+    fn py_encode_checked(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+    ) -> PyResult<Vec<Rank>> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            self.encode_checked(
+                text,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    /// `policy` is "allow", "raise" or "treat_as_text", see `SpecialTokenPolicy`.
+    #[pyo3(name = "encode_with_policy")]
+    fn py_encode_with_policy(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: HashSet<&str>,
+        policy: &str,
+    ) -> PyResult<Vec<Rank>> {
+        let policy = match policy {
+            "allow" => SpecialTokenPolicy::Allow,
+            "raise" => SpecialTokenPolicy::Raise,
+            "treat_as_text" => SpecialTokenPolicy::TreatAsText,
+            _ => {
+                return Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+                    "special_token_policy must be \"allow\", \"raise\" or \"treat_as_text\", \
+                     not {:?}",
+                    policy
+                )))
+            }
+        };
+        py.allow_threads(|| self.encode_with_policy(text, &allowed_special, policy))
+            .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    fn _encode_bytes(&self, py: Python, bytes: &[u8]) -> Vec<Rank> {
+        py.allow_threads(|| self._encode_bytes_native(bytes))
+    }
+
+    #[pyo3(name = "encode_ordinary_batch")]
+    fn py_encode_ordinary_batch(
+        &self,
+        py: Python,
+        texts: Vec<&str>,
+        num_threads: usize,
+    ) -> PyResult<Vec<Vec<Rank>>> {
+        Ok(py.allow_threads(|| self.encode_ordinary_batch(&texts, num_threads))?)
+    }
+
+    #[pyo3(name = "encode_batch")]
+    fn py_encode_batch(
+        &self,
+        py: Python,
+        texts: Vec<&str>,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+        num_threads: usize,
+    ) -> PyResult<Vec<Vec<Rank>>> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        Ok(py.allow_threads(|| {
+            self.encode_batch_checked(
+                &texts,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+                num_threads,
+            )
+        })?)
+    }
+
+    #[allow(clippy::type_complexity)]
+    #[pyo3(name = "encode_with_offsets", signature = (text, allowed_special, disallowed_special, char_offsets = false))]
+    fn py_encode_with_offsets(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+        char_offsets: bool,
+    ) -> PyResult<(Vec<Rank>, Vec<(usize, usize)>)> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            let (tokens, offsets) = self.encode_with_offsets_checked(
+                text,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )?;
+            if char_offsets {
+                Ok((tokens, byte_offsets_to_char_offsets(text, &offsets)))
+            } else {
+                Ok((tokens, offsets))
+            }
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    #[pyo3(name = "encode_truncated", signature = (text, max_tokens, allowed_special, disallowed_special, char_offsets = false))]
+    fn py_encode_truncated(
+        &self,
+        py: Python,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+        char_offsets: bool,
+    ) -> PyResult<(Vec<Rank>, usize)> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            let (tokens, cut) = self.encode_truncated_checked(
+                text,
+                max_tokens,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )?;
+            Ok((tokens, to_offset(text, cut, char_offsets)))
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    #[pyo3(name = "encode_truncated_tail", signature = (text, max_tokens, allowed_special, disallowed_special, char_offsets = false))]
+    fn py_encode_truncated_tail(
+        &self,
+        py: Python,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+        char_offsets: bool,
+    ) -> PyResult<(Vec<Rank>, usize)> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            let (tokens, start) = self.encode_truncated_tail_checked(
+                text,
+                max_tokens,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )?;
+            Ok((tokens, to_offset(text, start, char_offsets)))
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    #[allow(clippy::type_complexity)]
+    #[pyo3(name = "encode_truncated_middle", signature = (text, max_tokens, allowed_special, disallowed_special, char_offsets = false))]
+    fn py_encode_truncated_middle(
+        &self,
+        py: Python,
+        text: &str,
+        max_tokens: usize,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+        char_offsets: bool,
+    ) -> PyResult<(Vec<Rank>, Vec<Rank>, (usize, usize))> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            let (head, tail, (cut, start)) = self.encode_truncated_middle_checked(
+                text,
+                max_tokens,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )?;
+            let dropped = (
+                to_offset(text, cut, char_offsets),
+                to_offset(text, start, char_offsets),
+            );
+            Ok((head, tail, dropped))
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    #[pyo3(name = "chunk_text", signature = (text, max_tokens, overlap = 0, boundary = "piece", char_offsets = false))]
+    fn py_chunk_text(
+        &self,
+        py: Python,
+        text: &str,
+        max_tokens: usize,
+        overlap: usize,
+        boundary: &str,
+        char_offsets: bool,
+    ) -> PyResult<Vec<(String, (usize, usize))>> {
+        let boundary = match boundary {
+            "token" => ChunkBoundary::Token,
+            "piece" => ChunkBoundary::Piece,
+            "newline" => ChunkBoundary::Newline,
+            "sentence" => ChunkBoundary::Sentence,
+            _ => {
+                return Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+                    "unknown boundary {:?}, expected one of \"token\", \"piece\", \"newline\" or \"sentence\"",
+                    boundary
+                )))
+            }
+        };
+        py.allow_threads(|| {
+            let chunks = self.chunk_text(text, max_tokens, overlap, boundary)?;
+            let mut ranges: Vec<(usize, usize)> = chunks.iter().map(|c| c.1).collect();
+            if char_offsets {
+                ranges = byte_offsets_to_char_offsets(text, &ranges);
+            }
+            Ok(chunks
+                .into_iter()
+                .zip(ranges)
+                .map(|((chunk, _), range)| (chunk.to_owned(), range))
+                .collect())
+        })
+    }
+
+    #[pyo3(name = "count_tokens_ordinary")]
+    fn py_count_tokens_ordinary(&self, py: Python, text: &str) -> usize {
+        py.allow_threads(|| self.count_tokens_ordinary(text))
+    }
+
+    #[pyo3(name = "count_tokens")]
+    fn py_count_tokens(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+    ) -> PyResult<usize> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        py.allow_threads(|| {
+            self.count_tokens_checked(
+                text,
+                to_special_token_set(&allowed_special),
+                to_special_token_set(&disallowed_special),
+            )
+        })
+        .map_err(|e| disallowed_special_token_error(py, e))
+    }
+
+    #[pyo3(name = "encode_with_unstable")]
+    fn py_encode_with_unstable(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: &PyAny,
+        disallowed_special: &PyAny,
+    ) -> PyResult<Py<PyTuple>> {
+        let allowed_special = extract_special_tokens(allowed_special)?;
+        let disallowed_special = extract_special_tokens(disallowed_special)?;
+        let (tokens, completions) = py
+            .allow_threads(|| {
+                self.encode_with_unstable_checked(
+                    text,
+                    to_special_token_set(&allowed_special),
+                    to_special_token_set(&disallowed_special),
+                )
+            })
+            .map_err(|e| disallowed_special_token_error(py, e))?;
+        let py_completions =
+            PyList::new(py, completions.iter().map(|seq| PyList::new(py, &seq[..])));
+        Ok((tokens, py_completions).into_py(py))
+    }
+
+    #[pyo3(name = "encode_single_token")]
+    fn py_encode_single_token(&self, piece: &[u8]) -> PyResult<Rank> {
+        self.encode_single_token(piece)
+            .ok_or_else(|| PyErr::new::<exceptions::PyKeyError, _>(piece.to_owned()))
+    }
+
+    #[pyo3(name = "encode_single_piece")]
+    fn py_encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
+        self.encode_single_piece(piece)
+    }
+
+    // ====================
+    // Decoding
+    // ====================
+
+    #[pyo3(name = "decode_bytes")]
+    #[pyo3(signature = (tokens, unknown_tokens="raise"))]
+    fn py_decode_bytes(
+        &self,
+        py: Python,
+        tokens: Vec<Rank>,
+        unknown_tokens: &str,
+    ) -> PyResult<Py<PyBytes>> {
+        let unknown = unknown_token_policy(unknown_tokens)?;
+        let bytes = py.allow_threads(|| self.decode_bytes_with_policy(&tokens, unknown))?;
+        Ok(PyBytes::new(py, &bytes).into())
+    }
+
+    #[pyo3(name = "decode_batch")]
+    #[pyo3(signature = (batch, num_threads, unknown_tokens="raise"))]
+    fn py_decode_batch(
+        &self,
+        py: Python,
+        batch: Vec<Vec<Rank>>,
+        num_threads: usize,
+        unknown_tokens: &str,
+    ) -> PyResult<Vec<Py<PyBytes>>> {
+        let unknown = unknown_token_policy(unknown_tokens)?;
+        Ok(py
+            .allow_threads(|| self.decode_batch_with_policy(&batch, unknown, num_threads))?
+            .iter()
+            .map(|bytes| PyBytes::new(py, bytes).into())
+            .collect())
+    }
+
+    #[pyo3(name = "decode_single_token_bytes")]
+    fn py_decode_single_token_bytes(&self, py: Python, token: Rank) -> PyResult<Py<PyBytes>> {
+        self.decode_single_token_bytes(token)
+            .map(|bytes| PyBytes::new(py, bytes).into())
+            .ok_or_else(|| PyErr::new::<exceptions::PyKeyError, _>(token.to_string()))
+    }
+
+    #[pyo3(name = "streaming_decoder")]
+    fn py_streaming_decoder(slf: PyRef<Self>) -> StreamingDecoder {
+        StreamingDecoder {
+            bpe: slf.into(),
+            pending: vec![],
+        }
+    }
+
+    // ====================
+    // Miscellaneous
+    // ====================
+
+    #[pyo3(name = "token_byte_values")]
+    fn py_token_byte_values(&self, py: Python) -> Vec<Py<PyBytes>> {
+        self.token_byte_values()
+            .iter()
+            .map(|x| PyBytes::new(py, x).into())
+            .collect()
+    }
+
+    #[pyo3(name = "to_hf_tokenizer_json")]
+    fn py_to_hf_tokenizer_json(&self, py: Python) -> String {
+        py.allow_threads(|| self.to_hf_tokenizer_json())
+    }
+}
+
+fn unknown_token_policy(name: &str) -> PyResult<UnknownTokenPolicy> {
+    match name {
+        "raise" => Ok(UnknownTokenPolicy::Raise),
+        "skip" => Ok(UnknownTokenPolicy::Skip),
+        "replace" => Ok(UnknownTokenPolicy::Replace),
+        _ => Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+            "unknown_tokens must be \"raise\", \"skip\" or \"replace\", not {:?}",
+            name
+        ))),
+    }
+}
+
+/// Reads "all" as `None`, and anything else as a collection of special tokens
+fn extract_special_tokens(tokens: &PyAny) -> PyResult<Option<HashSet<&str>>> {
+    if let Ok(tokens) = tokens.extract::<&str>() {
+        if tokens == "all" {
+            return Ok(None);
+        }
+        return Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+            "expected \"all\" or a collection of special tokens, not {:?}",
+            tokens
+        )));
+    }
+    tokens
+        .iter()?
+        .map(|token| token?.extract())
+        .collect::<PyResult<_>>()
+        .map(Some)
+}
+
+fn to_special_token_set<'a>(tokens: &'a Option<HashSet<&'a str>>) -> SpecialTokenSet<'a> {
+    tokens
+        .as_ref()
+        .map_or(SpecialTokenSet::All, SpecialTokenSet::Only)
+}
+
+/// Turns a byte offset into `text` into a char offset (i.e. a Python string index) if asked to
+fn to_offset(text: &str, byte_offset: usize, char_offsets: bool) -> usize {
+    if char_offsets {
+        text[..byte_offset].chars().count()
+    } else {
+        byte_offset
+    }
+}
+
+/// Like `crate::stream::StreamingDecoder`, but keeps the `CoreBPE` alive itself.
+#[pyclass]
+struct StreamingDecoder {
+    bpe: Py<CoreBPE>,
+    pending: Vec<u8>,
+}
+
+#[pymethods]
+impl StreamingDecoder {
+    fn push(&mut self, py: Python, tokens: Vec<Rank>) -> PyResult<String> {
+        self.pending
+            .extend(self.bpe.borrow(py).decode_bytes(&tokens)?);
+        Ok(take_complete_utf8(&mut self.pending))
+    }
+
+    fn finish(&mut self) -> String {
+        let ret = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        ret
+    }
+
+    fn pending(&self, py: Python) -> Py<PyBytes> {
+        PyBytes::new(py, &self.pending).into()
+    }
+}
+
+#[pyfunction]
+#[pyo3(name = "load_tiktoken_bpe", signature = (contents, expected_hash = None))]
+fn py_load_tiktoken_bpe(
+    py: Python,
+    contents: &[u8],
+    expected_hash: Option<&str>,
+) -> PyResult<Py<PyDict>> {
+    let ranks = py.allow_threads(|| load_tiktoken_bpe(contents, expected_hash))?;
+    let dict = PyDict::new(py);
+    for (token, rank) in ranks {
+        dict.set_item(PyBytes::new(py, &token), rank)?;
+    }
+    Ok(dict.into())
+}
+
+/// Returns the keyword arguments for `Encoding`, other than the name
+#[pyfunction]
+#[pyo3(name = "load_hf_tokenizer_json")]
+fn py_load_hf_tokenizer_json(py: Python, contents: &[u8]) -> PyResult<Py<PyDict>> {
+    let tokenizer = py.allow_threads(|| parse_hf_tokenizer_json(contents))?;
+    let mergeable_ranks = PyDict::new(py);
+    for (token, rank) in tokenizer.encoder {
+        mergeable_ranks.set_item(PyBytes::new(py, &token), rank)?;
+    }
+    let merges = PyList::new(
+        py,
+        tokenizer.merges.iter().map(|(left, right)| {
+            PyTuple::new(py, [PyBytes::new(py, left), PyBytes::new(py, right)])
+        }),
+    );
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("pat_str", tokenizer.pattern)?;
+    kwargs.set_item("mergeable_ranks", mergeable_ranks)?;
+    kwargs.set_item("special_tokens", tokenizer.special_tokens_encoder)?;
+    kwargs.set_item("merges", merges)?;
+    kwargs.set_item("ignore_merges", tokenizer.ignore_merges)?;
+    // Like `HfTokenizer::into_core_bpe`
+    kwargs.set_item("keep_unmatched", true)?;
+    Ok(kwargs.into())
+}
+
+/// Returns a dict for each problem, with the kind of problem, a message and the details
+#[pyfunction]
+#[pyo3(name = "validate_vocab")]
+fn py_validate_vocab(
+    py: Python,
+    mergeable_ranks: HashMap<Vec<u8>, Rank>,
+    special_tokens: HashMap<String, Rank>,
+    pattern: &str,
+) -> PyResult<Py<PyList>> {
+    let errors =
+        match py.allow_threads(|| validate_vocab(&mergeable_ranks, &special_tokens, pattern)) {
+            Ok(()) => vec![],
+            Err(errors) => errors,
+        };
+    let ret = PyList::empty(py);
+    for error in errors {
+        let dict = PyDict::new(py);
+        dict.set_item("message", error.to_string())?;
+        let kind = match error {
+            VocabError::InvalidPattern(_) => "invalid_pattern",
+            VocabError::MissingByte(b) => {
+                dict.set_item("byte", b)?;
+                "missing_byte"
+            }
+            VocabError::DuplicateRank { rank, tokens } => {
+                dict.set_item("rank", rank)?;
+                dict.set_item(
+                    "tokens",
+                    (PyBytes::new(py, &tokens[0]), PyBytes::new(py, &tokens[1])),
+                )?;
+                "duplicate_rank"
+            }
+            VocabError::SpecialTokenCollision {
+                special_token,
+                rank,
+                other,
+            } => {
+                dict.set_item("special_token", special_token)?;
+                dict.set_item("rank", rank)?;
+                dict.set_item("other", PyBytes::new(py, &other))?;
+                "special_token_collision"
+            }
+            VocabError::UnreachableToken { token, rank } => {
+                dict.set_item("token", PyBytes::new(py, &token))?;
+                dict.set_item("rank", rank)?;
+                "unreachable_token"
+            }
+        };
+        dict.set_item("kind", kind)?;
+        ret.append(dict)?;
+    }
+    Ok(ret.into())
+}
+
+#[pyfunction]
+#[pyo3(name = "derive_merges")]
+fn py_derive_merges(
+    py: Python,
+    mergeable_ranks: HashMap<Vec<u8>, Rank>,
+) -> (Py<PyList>, Py<PyList>) {
+    let derived = py.allow_threads(|| derive_merges(&mergeable_ranks));
+    let merges = PyList::new(
+        py,
+        derived.merges.iter().map(|(left, right)| {
+            PyTuple::new(py, [PyBytes::new(py, left), PyBytes::new(py, right)])
+        }),
+    );
+    let underivable = PyList::new(
+        py,
+        derived
+            .underivable
+            .iter()
+            .map(|token| PyBytes::new(py, token)),
+    );
+    (merges.into(), underivable.into())
+}
+
+#[pyfunction]
+#[pyo3(name = "merges_txt")]
+fn py_merges_txt(py: Python, merges: Vec<(Vec<u8>, Vec<u8>)>) -> PyResult<Py<PyBytes>> {
+    let mut contents = vec![];
+    write_merges_txt(&merges, &mut contents)?;
+    Ok(PyBytes::new(py, &contents).into())
+}
+
+#[pyfunction]
+#[pyo3(name = "train_bpe", signature = (texts, pattern, vocab_size, special_tokens = vec![]))]
+fn py_train_bpe(
+    py: Python,
+    texts: &PyAny,
+    pattern: &str,
+    vocab_size: usize,
+    special_tokens: Vec<String>,
+) -> PyResult<(Py<PyDict>, HashMap<String, Rank>)> {
+    let to_py_err = |e: TrainError| PyErr::new::<exceptions::PyValueError, _>(e.to_string());
+    let mut trainer = BpeTrainer::new(pattern, special_tokens).map_err(to_py_err)?;
+    // Copy the texts out first, so that splitting them doesn't hold the GIL either
+    let texts = texts
+        .iter()?
+        .map(|text| text?.extract())
+        .collect::<PyResult<Vec<String>>>()?;
+    let (ranks, special_ranks) = py
+        .allow_threads(|| {
+            for text in &texts {
+                trainer.feed(text);
+            }
+            trainer.train(vocab_size)
+        })
+        .map_err(to_py_err)?;
+    let dict = PyDict::new(py);
+    for (token, rank) in ranks {
+        dict.set_item(PyBytes::new(py, &token), rank)?;
+    }
+    Ok((dict.into(), special_ranks))
+}
+
+#[pyfunction]
+#[pyo3(name = "extend_bpe")]
+fn py_extend_bpe(
+    py: Python,
+    texts: &PyAny,
+    pattern: &str,
+    mergeable_ranks: HashMap<Vec<u8>, Rank>,
+    special_tokens: HashMap<String, Rank>,
+    num_merges: usize,
+) -> PyResult<Py<PyDict>> {
+    let to_py_err = |e: TrainError| PyErr::new::<exceptions::PyValueError, _>(e.to_string());
+    let mut trainer =
+        BpeTrainer::new(pattern, special_tokens.keys().cloned()).map_err(to_py_err)?;
+    let texts = texts
+        .iter()?
+        .map(|text| text?.extract())
+        .collect::<PyResult<Vec<String>>>()?;
+    let first_rank = first_new_rank(&mergeable_ranks, &special_tokens);
+    let ranks = py
+        .allow_threads(|| {
+            for text in &texts {
+                trainer.feed(text);
+            }
+            trainer.extend(&mergeable_ranks, first_rank, num_merges)
+        })
+        .map_err(to_py_err)?;
+    let dict = PyDict::new(py);
+    for (token, rank) in ranks {
+        dict.set_item(PyBytes::new(py, &token), rank)?;
+    }
+    Ok(dict.into())
+}
+
+/// Raised when the text has a disallowed special token in it. `offset` is where the token
+/// starts in the text, in UTF-8 bytes.
+#[pyclass(extends = exceptions::PyValueError)]
+struct DisallowedSpecialTokenError {
+    #[pyo3(get)]
+    token: String,
+    #[pyo3(get)]
+    offset: usize,
+}
+
+fn disallowed_special_token_error(py: Python, e: DisallowedSpecialError) -> PyErr {
+    match py
+        .get_type::<DisallowedSpecialTokenError>()
+        .call1((e.token, e.offset))
+    {
+        Ok(err) => PyErr::from_value(err),
+        Err(err) => err,
+    }
+}
+
+#[pymethods]
+impl DisallowedSpecialTokenError {
+    #[new]
+    fn new(token: String, offset: usize) -> Self {
+        DisallowedSpecialTokenError { token, offset }
+    }
+
+    fn __str__(&self) -> String {
+        let token = format!("{:?}", self.token);
+        format!(
+            "Encountered text corresponding to disallowed special token {token} at byte {offset}.\n\
+             If you want this text to be encoded as a special token, pass it to \
+             `allowed_special`, e.g. `allowed_special={{{token}, ...}}`.\n\
+             If you want this text to be encoded as normal text, disable the check for this \
+             token by passing `disallowed_special=(enc.special_tokens_set - {{{token}}})`.\n\
+             To disable this check for all special tokens, pass `disallowed_special=()`.\n",
+            token = token,
+            offset = self.offset,
+        )
+    }
+}
+
+#[pymodule]
+fn _tiktoken(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<CoreBPE>()?;
+    m.add_class::<StreamingDecoder>()?;
+    m.add_class::<DisallowedSpecialTokenError>()?;
+    m.add_function(wrap_pyfunction!(py_load_tiktoken_bpe, m)?)?;
+    m.add_function(wrap_pyfunction!(py_load_hf_tokenizer_json, m)?)?;
+    m.add_function(wrap_pyfunction!(py_validate_vocab, m)?)?;
+    m.add_function(wrap_pyfunction!(py_derive_merges, m)?)?;
+    m.add_function(wrap_pyfunction!(py_merges_txt, m)?)?;
+    m.add_function(wrap_pyfunction!(py_train_bpe, m)?)?;
+    m.add_function(wrap_pyfunction!(py_extend_bpe, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,445 @@
+//! Incremental encoding and decoding, for text and tokens that arrive in chunks.
+
+use std::collections::HashSet;
+
+use crate::{CoreBPE, DecodeError, Rank};
+
+/// Up to this many bytes, the buffer is split again on every push.
+const ALWAYS_SPLIT_LEN: usize = 256;
+
+/// Encodes text that arrives in chunks. Concatenating the output of every `push` and of
+/// `finish` gives the same tokens as `CoreBPE::encode` of the concatenated chunks.
+///
+/// Tokens are emitted once more text can no longer change them. Like
+/// `_increase_last_piece_token_len`, this assumes that regex splits are mostly stable: the last
+/// two pieces and trailing whitespace pieces are held back, but a piece further back is assumed
+/// not to depend on text that comes later. That holds for the patterns of the tiktoken
+/// encodings, but not for every regex, e.g. one with a longer lookahead.
+pub struct StreamingEncoder<'a> {
+    bpe: &'a CoreBPE,
+    allowed_special: HashSet<&'a str>,
+    /// Every proper prefix of an allowed special token, since text that ends in one of these
+    /// could still become a special token
+    special_prefixes: HashSet<&'a str>,
+    max_special_prefix_len: usize,
+    /// Once splitting a long buffer hasn't made anything stable, it's only split again when it
+    /// has doubled. Otherwise a long piece arriving in small chunks, like a line of base64 or a
+    /// run of whitespace, is quadratic. This only delays tokens, they come out the same.
+    split_at_len: usize,
+    buffer: String,
+}
+
+impl<'a> StreamingEncoder<'a> {
+    pub fn new(bpe: &'a CoreBPE, allowed_special: HashSet<&'a str>) -> Self {
+        let special_prefixes: HashSet<&'a str> = allowed_special
+            .iter()
+            .flat_map(|special| {
+                (1..special.len())
+                    .filter(|&i| special.is_char_boundary(i))
+                    .map(|i| &special[..i])
+            })
+            .collect();
+        let max_special_prefix_len = special_prefixes.iter().map(|p| p.len()).max().unwrap_or(0);
+        StreamingEncoder {
+            bpe,
+            allowed_special,
+            special_prefixes,
+            max_special_prefix_len,
+            split_at_len: 0,
+            buffer: String::new(),
+        }
+    }
+
+    /// Adds a chunk of text and returns the tokens that have become stable.
+    pub fn push(&mut self, chunk: &str) -> Vec<Rank> {
+        self.buffer.push_str(chunk);
+        if self.buffer.len() < self.split_at_len {
+            return vec![];
+        }
+        // The buffer starts at the first piece that wasn't stable last time, so this only splits
+        // text that could still change
+        let (units, num_stable) = self._split_stable();
+        if num_stable == 0 {
+            if self.buffer.len() > ALWAYS_SPLIT_LEN {
+                self.split_at_len = 2 * self.buffer.len();
+            }
+            return vec![];
+        }
+        // Encode the stable pieces as they were split in the context of the whole buffer, since
+        // splitting the stable prefix on its own can give different pieces
+        let mut tokens = vec![];
+        for unit in &units[..num_stable] {
+            let piece = &self.buffer[unit.start..unit.end];
+            if unit.is_special {
+                tokens.push(self.bpe.special_tokens_encoder[piece]);
+                continue;
+            }
+            match self.bpe._piece_token(piece.as_bytes()) {
+                Some(token) => tokens.push(token),
+                None => tokens.extend(&self.bpe._byte_pair_encode(piece.as_bytes())),
+            }
+        }
+        let stable_len = units
+            .get(num_stable)
+            .map_or(self.buffer.len(), |unit| unit.start);
+        self.buffer.drain(..stable_len);
+        self.split_at_len = 0;
+        tokens
+    }
+
+    /// Encodes whatever text is still buffered.
+    pub fn finish(self) -> Vec<Rank> {
+        self.bpe
+            ._encode_native(&self.buffer, &self.allowed_special)
+            .0
+    }
+
+    /// The text that has been pushed but not yet encoded.
+    pub fn pending(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Splits the buffer into pieces and allowed special tokens, and returns how many of those
+    /// can't change when more text is pushed.
+    fn _split_stable(&self) -> (Vec<Unit>, usize) {
+        let text = self.buffer.as_str();
+        let regex = self.bpe._get_regex();
+
+        // Compare this logic to _encode_native
+        let mut units: Vec<Unit> = vec![];
+        let mut start = 0;
+        loop {
+            let next_special = self
+                .bpe
+                ._find_allowed_special(text, start, &self.allowed_special);
+            let end = next_special.map_or(text.len(), |m| m.start());
+
+            for range in self.bpe._pieces(&regex, &text[start..end]) {
+                units.push(Unit {
+                    start: start + range.start,
+                    end: start + range.end,
+                    is_special: false,
+                });
+            }
+
+            match next_special {
+                Some(m) => {
+                    units.push(Unit {
+                        start: m.start(),
+                        end: m.end(),
+                        is_special: true,
+                    });
+                    start = m.end();
+                }
+                None => break,
+            }
+        }
+
+        // Hold back anything that could turn out to be the start of an allowed special token.
+        // This also holds back special tokens that are a prefix of a longer special token.
+        let special_prefix_len = (1..=self.max_special_prefix_len.min(text.len()))
+            .rev()
+            .find(|&i| {
+                text.is_char_boundary(text.len() - i)
+                    && self.special_prefixes.contains(&text[text.len() - i..])
+            })
+            .unwrap_or(0);
+        let limit = text.len() - special_prefix_len;
+
+        // The last two pieces can still change. The last piece could always grow, and the one
+        // before it depends on what follows, e.g. with r50k_base "'" + "r" becomes "'re". Pieces
+        // never span special tokens, so those are a safe place to cut.
+        let num_trailing_pieces = units
+            .iter()
+            .rev()
+            .take_while(|unit| !unit.is_special)
+            .count();
+        let mut num_stable = units
+            .iter()
+            .take(units.len() - num_trailing_pieces.min(2))
+            .take_while(|unit| unit.end <= limit)
+            .count();
+        // Whitespace pieces can merge with what comes next, e.g. with cl100k_base "\n" + " " can
+        // become "\n \n"
+        while num_stable > 0 && units[num_stable - 1].is_whitespace(text) {
+            num_stable -= 1;
+        }
+        (units, num_stable)
+    }
+}
+
+struct Unit {
+    start: usize,
+    end: usize,
+    is_special: bool,
+}
+
+impl Unit {
+    fn is_whitespace(&self, text: &str) -> bool {
+        !self.is_special && text[self.start..self.end].chars().all(char::is_whitespace)
+    }
+}
+
+/// Decodes tokens that arrive a few at a time. Token boundaries don't have to line up with
+/// character boundaries, so incomplete UTF-8 at the end is held back until the rest of it arrives.
+pub struct StreamingDecoder<'a> {
+    bpe: &'a CoreBPE,
+    pending: Vec<u8>,
+}
+
+impl<'a> StreamingDecoder<'a> {
+    pub fn new(bpe: &'a CoreBPE) -> Self {
+        StreamingDecoder {
+            bpe,
+            pending: vec![],
+        }
+    }
+
+    /// Adds tokens and returns the text that is now complete. If any of the tokens is unknown,
+    /// none of them are added.
+    pub fn push(&mut self, tokens: &[Rank]) -> Result<String, DecodeError> {
+        self.pending.extend(self.bpe.decode_bytes(tokens)?);
+        Ok(take_complete_utf8(&mut self.pending))
+    }
+
+    /// Returns the rest of the text. Incomplete UTF-8 at the end becomes U+FFFD.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+
+    /// The bytes that have been decoded but not yet returned.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+}
+
+/// Removes and returns everything in `pending` except an incomplete UTF-8 sequence at the end.
+/// Bytes that can never become valid UTF-8 are replaced with U+FFFD, like `errors="replace"`.
+pub(crate) fn take_complete_utf8(pending: &mut Vec<u8>) -> String {
+    let mut ret = String::with_capacity(pending.len());
+    let mut start = 0;
+    loop {
+        match std::str::from_utf8(&pending[start..]) {
+            Ok(s) => {
+                ret.push_str(s);
+                start = pending.len();
+                break;
+            }
+            Err(e) => {
+                let valid_end = start + e.valid_up_to();
+                // Safe because from_utf8 just told us this much is valid
+                ret.push_str(unsafe { std::str::from_utf8_unchecked(&pending[start..valid_end]) });
+                match e.error_len() {
+                    Some(len) => {
+                        ret.push(char::REPLACEMENT_CHARACTER);
+                        start = valid_end + len;
+                    }
+                    None => {
+                        start = valid_end;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    pending.drain(..start);
+    ret
+}
+
+impl CoreBPE {
+    pub fn streaming_encoder<'a>(
+        &'a self,
+        allowed_special: HashSet<&'a str>,
+    ) -> StreamingEncoder<'a> {
+        StreamingEncoder::new(self, allowed_special)
+    }
+
+    pub fn streaming_decoder(&self) -> StreamingDecoder<'_> {
+        StreamingDecoder::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::take_complete_utf8;
+    use crate::tests::{
+        setup_core_bpe, setup_encoder, Lcg, CL100K_PATTERN, O200K_PATTERN, R50K_PATTERN,
+    };
+    use crate::CoreBPE;
+
+    const TEXTS: &[&str] = &[
+        "hello world",
+        "hello world\n\n  \n hello",
+        "it's 12345 o'clock\n\n\n    indented\n\ttabs \n",
+        "héllo wörld, the end <|endoftext|> and <|fim_prefix|>after<|endoftext|>",
+        "<|endoftext|><|endoftext|><|endo",
+        "    leading and trailing     ",
+        "numbers 1234567890 and punctuation!!! ... ?!",
+        "you're sure it'll work? don't  0 = 1",
+    ];
+
+    #[test]
+    fn test_streaming_encoder_matches_encode() {
+        let bpe = setup_core_bpe();
+        let allowed_special = HashSet::from(["<|endoftext|>", "<|fim_prefix|>"]);
+        for text in TEXTS {
+            let expected = bpe.encode(text, &allowed_special);
+            let chars: Vec<char> = text.chars().collect();
+            for chunk_len in 1..8 {
+                let mut encoder = bpe.streaming_encoder(allowed_special.clone());
+                let mut tokens = vec![];
+                for chunk in chars.chunks(chunk_len) {
+                    tokens.extend(encoder.push(&chunk.iter().collect::<String>()));
+                }
+                tokens.extend(encoder.finish());
+                assert_eq!(tokens, expected, "{:?} in chunks of {}", text, chunk_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_random_chunks() {
+        // Bits of text that the patterns split in interesting ways
+        const ATOMS: &[&str] = &[
+            "a",
+            "b",
+            "Z",
+            "AB",
+            "é",
+            "日本",
+            " ",
+            "  ",
+            "\n",
+            "\r\n",
+            "\t",
+            "1",
+            "234",
+            "'",
+            "s",
+            "ll",
+            "re",
+            "t",
+            "!",
+            "...",
+            "/",
+            "<|",
+            "|>",
+            "<|endoftext|>",
+            "<|fim_prefix|>",
+        ];
+        // Every pair of these is a token, so that pieces split in the wrong place almost always
+        // encode differently
+        let chars: Vec<char> = "abZAB 1234!'s\n\t/<|>".chars().collect();
+        let pairs: Vec<String> = chars
+            .iter()
+            .flat_map(|&a| chars.iter().map(move |&b| [a, b].iter().collect()))
+            .collect();
+        let pairs: Vec<&[u8]> = pairs.iter().map(|pair| pair.as_bytes()).collect();
+        let special_tokens_encoder = [
+            ("<|endoftext|>".to_string(), 10000),
+            ("<|fim_prefix|>".to_string(), 10001),
+        ];
+        let allowed_specials = [
+            HashSet::new(),
+            HashSet::from(["<|endoftext|>"]),
+            HashSet::from(["<|endoftext|>", "<|fim_prefix|>"]),
+        ];
+
+        let mut rng = Lcg(3);
+        for pattern in [R50K_PATTERN, CL100K_PATTERN, O200K_PATTERN] {
+            let bpe = CoreBPE::new(
+                setup_encoder(&pairs),
+                special_tokens_encoder.clone(),
+                pattern,
+            )
+            .unwrap();
+            for _ in 0..500 {
+                let text: String = (0..rng.next() % 30)
+                    .map(|_| ATOMS[rng.next() % ATOMS.len()])
+                    .collect();
+                let allowed_special = &allowed_specials[rng.next() % allowed_specials.len()];
+                let expected = bpe.encode(&text, allowed_special);
+
+                let mut encoder = bpe.streaming_encoder(allowed_special.clone());
+                let mut tokens = vec![];
+                let mut rest = text.as_str();
+                while !rest.is_empty() {
+                    let mut split = 1 + rng.next() % rest.len();
+                    while !rest.is_char_boundary(split) {
+                        split += 1;
+                    }
+                    tokens.extend(encoder.push(&rest[..split]));
+                    rest = &rest[split..];
+                }
+                tokens.extend(encoder.finish());
+                assert_eq!(tokens, expected, "{:?} with {:?}", text, pattern);
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_emits_early() {
+        let bpe = setup_core_bpe();
+        let mut encoder = bpe.streaming_encoder(HashSet::from(["<|endoftext|>"]));
+        assert!(encoder.push("hello").is_empty());
+        assert!(encoder.push(" world").is_empty());
+        assert_eq!(encoder.push(" and"), bpe.encode_ordinary("hello"));
+        assert_eq!(encoder.pending(), " world and");
+        assert_eq!(encoder.push("<|endo"), bpe.encode_ordinary(" world and"));
+        assert_eq!(encoder.push("ftext|>"), vec![1000]);
+        assert!(encoder.finish().is_empty());
+
+        // A long piece is only split again once the buffer has doubled
+        let mut encoder = bpe.streaming_encoder(HashSet::new());
+        let mut tokens = vec![];
+        for _ in 0..1000 {
+            tokens.extend(encoder.push("a"));
+        }
+        assert!(tokens.is_empty());
+        tokens.extend(encoder.push(" b c"));
+        assert!(tokens.is_empty());
+        assert_eq!(encoder.pending().len(), 1004);
+        tokens.extend(encoder.push(&" d".repeat(500)));
+        assert!(encoder.pending().len() < 10);
+        tokens.extend(encoder.finish());
+        let text = format!("{} b c{}", "a".repeat(1000), " d".repeat(500));
+        assert_eq!(tokens, bpe.encode_ordinary(&text));
+    }
+
+    #[test]
+    fn test_streaming_decoder() {
+        let bpe = setup_core_bpe();
+        let text = "héllo 日本語 wörld 🎉!";
+        let tokens = bpe.encode_ordinary(text);
+        // Most of these characters are split across several single byte tokens
+        assert!(tokens.len() > text.chars().count());
+
+        let mut decoder = bpe.streaming_decoder();
+        let mut decoded = String::new();
+        for token in &tokens {
+            let chunk = decoder.push(&[*token]).unwrap();
+            assert!(!chunk.contains(char::REPLACEMENT_CHARACTER));
+            decoded.push_str(&chunk);
+        }
+        assert!(decoder.pending().is_empty());
+        assert_eq!(decoded + &decoder.finish(), text);
+
+        let tokens = bpe.encode_ordinary("日");
+        let mut decoder = bpe.streaming_decoder();
+        assert_eq!(decoder.push(&tokens[..2]).unwrap(), "");
+        assert_eq!(decoder.pending(), b"\xe6\x97");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
+
+    #[test]
+    fn test_take_complete_utf8() {
+        let mut pending = b"ok \xff \xe6\x97".to_vec();
+        assert_eq!(take_complete_utf8(&mut pending), "ok \u{fffd} ");
+        assert_eq!(pending, b"\xe6\x97");
+        pending.push(0xa5);
+        assert_eq!(take_complete_utf8(&mut pending), "日");
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,576 @@
+//! Training BPE vocabularies, the fast equivalent of `bpe_train` in `tiktoken/_educational.py`.
+//!
+//! Texts are split into pieces with the same regex as `CoreBPE`, and identical pieces are
+//! counted once. Training then repeatedly merges the most common pair of adjacent tokens. Pair
+//! counts are kept up to date as we go, so each merge only touches the pieces it changes.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use fancy_regex::Regex;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{byte_pair_encode, Rank};
+
+#[derive(Debug)]
+pub enum TrainError {
+    InvalidPattern(fancy_regex::Error),
+    /// The vocabulary we're asked to extend can't be extended.
+    UnsupportedVocab(String),
+    /// The special tokens can't be matched in the training texts, e.g. because one is empty.
+    UnsupportedSpecialTokens(String),
+    /// There has to be room for all 256 single bytes and the special tokens.
+    VocabSizeTooSmall {
+        vocab_size: usize,
+        min: usize,
+    },
+}
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrainError::InvalidPattern(e) => write!(f, "Invalid pattern: {}", e),
+            TrainError::UnsupportedVocab(reason) => write!(f, "{}", reason),
+            TrainError::UnsupportedSpecialTokens(reason) => {
+                write!(f, "Unsupported special tokens: {}", reason)
+            }
+            TrainError::VocabSizeTooSmall { vocab_size, min } => write!(
+                f,
+                "vocab_size must be at least {} to fit all bytes and special tokens, got {}",
+                min, vocab_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {}
+
+impl From<fancy_regex::Error> for TrainError {
+    fn from(e: fancy_regex::Error) -> Self {
+        TrainError::InvalidPattern(e)
+    }
+}
+
+/// Collects the pieces of the training texts. Special tokens in the texts are skipped, so that
+/// they never end up as part of an ordinary token.
+pub struct BpeTrainer {
+    regex: Regex,
+    special_tokens: Vec<String>,
+    special_matcher: Option<AhoCorasick>,
+    piece_counts: HashMap<Vec<u8>, u64>,
+}
+
+impl BpeTrainer {
+    pub fn new<S>(pattern: &str, special_tokens: S) -> Result<Self, TrainError>
+    where
+        S: IntoIterator<Item = String>,
+    {
+        let regex = Regex::new(pattern)?;
+        let mut special_tokens: Vec<String> = special_tokens.into_iter().collect();
+        special_tokens.sort();
+        special_tokens.dedup();
+        if special_tokens.iter().any(|token| token.is_empty()) {
+            return Err(TrainError::UnsupportedSpecialTokens(
+                "a special token is empty".to_string(),
+            ));
+        }
+        let special_matcher = if special_tokens.is_empty() {
+            None
+        } else {
+            Some(
+                AhoCorasick::builder()
+                    .match_kind(MatchKind::LeftmostLongest)
+                    .build(&special_tokens)
+                    .map_err(|e| TrainError::UnsupportedSpecialTokens(e.to_string()))?,
+            )
+        };
+        Ok(BpeTrainer {
+            regex,
+            special_tokens,
+            special_matcher,
+            piece_counts: HashMap::default(),
+        })
+    }
+
+    /// Adds a training text.
+    pub fn feed(&mut self, text: &str) {
+        let mut start = 0;
+        if let Some(special_matcher) = &self.special_matcher {
+            for m in special_matcher.find_iter(text) {
+                count_pieces(&self.regex, &mut self.piece_counts, &text[start..m.start()]);
+                start = m.end();
+            }
+        }
+        count_pieces(&self.regex, &mut self.piece_counts, &text[start..]);
+    }
+
+    /// Trains a vocabulary of `vocab_size` tokens, including the 256 single bytes and the special
+    /// tokens, and returns the mergeable ranks and the special tokens in the form `CoreBPE::new`
+    /// takes them. Special tokens get the ranks after the mergeable ones.
+    ///
+    /// If the training texts run out of pairs to merge, the vocabulary ends up smaller. Ties
+    /// between equally common pairs go to the pair of lowest ranked tokens, so the result only
+    /// depends on the texts and not on the order they were fed in.
+    #[allow(clippy::type_complexity)]
+    pub fn train(
+        self,
+        vocab_size: usize,
+    ) -> Result<(HashMap<Vec<u8>, Rank>, HashMap<String, Rank>), TrainError> {
+        let min = 256 + self.special_tokens.len();
+        if vocab_size < min {
+            return Err(TrainError::VocabSizeTooSmall { vocab_size, min });
+        }
+        let tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        let mut merger = Merger::new(tokens, self.piece_counts);
+        merger.merge(vocab_size - self.special_tokens.len());
+
+        let mergeable_ranks: HashMap<Vec<u8>, Rank> = merger
+            .tokens
+            .into_iter()
+            .enumerate()
+            .map(|(rank, token)| (token, rank as Rank))
+            .collect();
+        let num_mergeable = mergeable_ranks.len();
+        let special_tokens = self
+            .special_tokens
+            .into_iter()
+            .enumerate()
+            .map(|(i, token)| (token, (num_mergeable + i) as Rank))
+            .collect();
+        Ok((mergeable_ranks, special_tokens))
+    }
+
+    /// Learns `num_merges` more tokens on top of `mergeable_ranks`, e.g. to add domain specific
+    /// tokens to an existing encoding. Existing tokens keep their ranks, so old tokens still
+    /// decode the same. New tokens get ranks from `first_rank` on, in the order they were
+    /// learned. Returns all the tokens.
+    ///
+    /// Since new tokens rank after all existing ones, encoding with the result first does
+    /// exactly what the existing encoding did, and then applies the new merges on top.
+    pub fn extend(
+        self,
+        mergeable_ranks: &HashMap<Vec<u8>, Rank>,
+        first_rank: Rank,
+        num_merges: usize,
+    ) -> Result<HashMap<Vec<u8>, Rank>, TrainError> {
+        if let Some(b) = (0..=255u8).find(|b| !mergeable_ranks.contains_key(&[*b][..])) {
+            return Err(TrainError::UnsupportedVocab(format!(
+                "the vocabulary is missing the single byte {:#04x}",
+                b
+            )));
+        }
+        if let Some((token, &rank)) = mergeable_ranks.iter().find(|(_, &r)| r >= first_rank) {
+            return Err(TrainError::UnsupportedVocab(format!(
+                "token {:?} has rank {}, but new tokens would start at {}",
+                String::from_utf8_lossy(token),
+                rank,
+                first_rank
+            )));
+        }
+
+        let mut tokens: Vec<(&Vec<u8>, Rank)> =
+            mergeable_ranks.iter().map(|(k, v)| (k, *v)).collect();
+        tokens.sort_by_key(|&(_, rank)| rank);
+        let num_existing = tokens.len();
+        let mut merger = Merger::new(
+            tokens.into_iter().map(|(token, _)| token.clone()).collect(),
+            self.piece_counts,
+        );
+        merger.merge(num_existing + num_merges);
+
+        let mut ranks = mergeable_ranks.clone();
+        for (i, token) in merger.tokens.drain(num_existing..).enumerate() {
+            ranks.insert(token, first_rank + i as Rank);
+        }
+        Ok(ranks)
+    }
+}
+
+fn count_pieces(regex: &Regex, piece_counts: &mut HashMap<Vec<u8>, u64>, text: &str) {
+    for mat in regex.find_iter(text) {
+        let piece = mat.unwrap().as_str().as_bytes();
+        match piece_counts.get_mut(piece) {
+            Some(count) => *count += 1,
+            None => {
+                piece_counts.insert(piece.to_vec(), 1);
+            }
+        }
+    }
+}
+
+/// Trains a vocabulary on `texts`, see `BpeTrainer::train`.
+#[allow(clippy::type_complexity)]
+pub fn train_bpe<I, T, S>(
+    texts: I,
+    pattern: &str,
+    special_tokens: S,
+    vocab_size: usize,
+) -> Result<(HashMap<Vec<u8>, Rank>, HashMap<String, Rank>), TrainError>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+    S: IntoIterator<Item = String>,
+{
+    let mut trainer = BpeTrainer::new(pattern, special_tokens)?;
+    for text in texts {
+        trainer.feed(text.as_ref());
+    }
+    trainer.train(vocab_size)
+}
+
+/// Learns `num_merges` more tokens on top of an existing encoding, see `BpeTrainer::extend`. New
+/// tokens get ranks after all existing tokens, including the special ones.
+pub fn extend_bpe<I, T>(
+    texts: I,
+    pattern: &str,
+    mergeable_ranks: &HashMap<Vec<u8>, Rank>,
+    special_tokens: &HashMap<String, Rank>,
+    num_merges: usize,
+) -> Result<HashMap<Vec<u8>, Rank>, TrainError>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+{
+    let mut trainer = BpeTrainer::new(pattern, special_tokens.keys().cloned())?;
+    for text in texts {
+        trainer.feed(text.as_ref());
+    }
+    let first_rank = first_new_rank(mergeable_ranks, special_tokens);
+    trainer.extend(mergeable_ranks, first_rank, num_merges)
+}
+
+/// The rank after every existing token, including the special ones, which is where `extend_bpe`
+/// starts new tokens.
+pub(crate) fn first_new_rank(
+    mergeable_ranks: &HashMap<Vec<u8>, Rank>,
+    special_tokens: &HashMap<String, Rank>,
+) -> Rank {
+    mergeable_ranks
+        .values()
+        .chain(special_tokens.values())
+        .max()
+        .map_or(0, |rank| rank + 1)
+}
+
+type Pair = (u32, u32);
+
+/// The state of training. Tokens are identified by their index in `tokens`, which is also the
+/// order they were added in.
+struct Merger {
+    tokens: Vec<Vec<u8>>,
+    ids: HashMap<Vec<u8>, u32>,
+    /// Each distinct piece as a sequence of tokens, and how often it occurs
+    words: Vec<(Vec<u32>, u64)>,
+    pair_counts: HashMap<Pair, u64>,
+    /// The words each pair occurs in. This may also list words the pair no longer occurs in.
+    pair_words: HashMap<Pair, HashSet<usize>>,
+    /// Entries are stale if their count no longer matches `pair_counts`. The most common pair
+    /// is on top, and of those the one with the lowest ids.
+    queue: BinaryHeap<(u64, Reverse<Pair>)>,
+}
+
+impl Merger {
+    /// `tokens` are the tokens we start with, in rank order. They have to contain every single
+    /// byte.
+    fn new(tokens: Vec<Vec<u8>>, piece_counts: HashMap<Vec<u8>, u64>) -> Self {
+        let ids: HashMap<Vec<u8>, u32> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id as u32))
+            .collect();
+        // Sort so that word indices, and hence everything else, don't depend on hash order
+        let mut piece_counts: Vec<(Vec<u8>, u64)> = piece_counts.into_iter().collect();
+        piece_counts.sort();
+        // Start from how the initial tokens encode each piece. Ids are in rank order, so they
+        // work as ranks.
+        let words: Vec<(Vec<u32>, u64)> = piece_counts
+            .into_iter()
+            .map(|(piece, count)| match ids.get(&piece) {
+                Some(&id) => (vec![id], count),
+                None => (byte_pair_encode(&piece, &ids), count),
+            })
+            .collect();
+
+        let mut pair_counts: HashMap<Pair, u64> = HashMap::default();
+        let mut pair_words: HashMap<Pair, HashSet<usize>> = HashMap::default();
+        for (i, (word, count)) in words.iter().enumerate() {
+            for pair in word.windows(2) {
+                let pair = (pair[0], pair[1]);
+                *pair_counts.entry(pair).or_default() += count;
+                pair_words.entry(pair).or_default().insert(i);
+            }
+        }
+        let queue = pair_counts
+            .iter()
+            .map(|(&pair, &count)| (count, Reverse(pair)))
+            .collect();
+
+        Merger {
+            tokens,
+            ids,
+            words,
+            pair_counts,
+            pair_words,
+            queue,
+        }
+    }
+
+    /// Merges the most common pair until there are `num_tokens` tokens or nothing left to merge.
+    fn merge(&mut self, num_tokens: usize) {
+        while self.tokens.len() < num_tokens {
+            let pair = match self._pop_most_common() {
+                Some(pair) => pair,
+                None => break,
+            };
+            let merged = [
+                self.tokens[pair.0 as usize].as_slice(),
+                self.tokens[pair.1 as usize].as_slice(),
+            ]
+            .concat();
+            // Different pairs can make the same bytes, in which case we reuse the token
+            let id = match self.ids.get(&merged) {
+                Some(&id) => id,
+                None => {
+                    let id = self.tokens.len() as u32;
+                    self.ids.insert(merged.clone(), id);
+                    self.tokens.push(merged);
+                    id
+                }
+            };
+            self._apply_merge(pair, id);
+        }
+    }
+
+    fn _pop_most_common(&mut self) -> Option<Pair> {
+        while let Some((count, Reverse(pair))) = self.queue.pop() {
+            if count > 0 && self.pair_counts.get(&pair) == Some(&count) {
+                return Some(pair);
+            }
+        }
+        None
+    }
+
+    /// Replaces every occurrence of `pair` with `id`, and updates the pair counts
+    fn _apply_merge(&mut self, pair: Pair, id: u32) {
+        let mut word_indices: Vec<usize> = self
+            .pair_words
+            .remove(&pair)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        word_indices.sort_unstable();
+
+        let mut changed: HashSet<Pair> = HashSet::new();
+        for i in word_indices {
+            let (word, count) = &mut self.words[i];
+            let count = *count;
+            if !word.windows(2).any(|w| (w[0], w[1]) == pair) {
+                continue;
+            }
+            let mut merged_word = Vec::with_capacity(word.len());
+            let mut j = 0;
+            while j < word.len() {
+                if j + 1 < word.len() && (word[j], word[j + 1]) == pair {
+                    merged_word.push(id);
+                    j += 2;
+                } else {
+                    merged_word.push(word[j]);
+                    j += 1;
+                }
+            }
+
+            for w in word.windows(2) {
+                let old_pair = (w[0], w[1]);
+                *self.pair_counts.get_mut(&old_pair).unwrap() -= count;
+                changed.insert(old_pair);
+            }
+            for w in merged_word.windows(2) {
+                let new_pair = (w[0], w[1]);
+                *self.pair_counts.entry(new_pair).or_default() += count;
+                self.pair_words.entry(new_pair).or_default().insert(i);
+                changed.insert(new_pair);
+            }
+            *word = merged_word;
+        }
+
+        for pair in changed {
+            let count = self.pair_counts[&pair];
+            if count == 0 {
+                self.pair_counts.remove(&pair);
+            } else {
+                self.queue.push((count, Reverse(pair)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::{extend_bpe, train_bpe, TrainError};
+    use crate::tests::{setup_encoder, CL100K_PATTERN};
+    use crate::{CoreBPE, Rank};
+
+    /// The same algorithm as `bpe_train` in `tiktoken/_educational.py`, with the same tie breaking
+    /// as the real trainer.
+    fn train_slow(texts: &[&str], pattern: &str, vocab_size: usize) -> HashMap<Vec<u8>, Rank> {
+        let regex = fancy_regex::Regex::new(pattern).unwrap();
+        let mut ranks = setup_encoder(&[]);
+        let mut words: Vec<Vec<Vec<u8>>> = texts
+            .iter()
+            .flat_map(|text| regex.find_iter(text))
+            .map(|mat| mat.unwrap().as_str().bytes().map(|b| vec![b]).collect())
+            .collect();
+        while ranks.len() < vocab_size {
+            let mut counts: HashMap<(Rank, Rank), usize> = HashMap::default();
+            for word in &words {
+                for pair in word.windows(2) {
+                    *counts
+                        .entry((ranks[&pair[0]], ranks[&pair[1]]))
+                        .or_default() += 1;
+                }
+            }
+            let best = match counts
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            {
+                Some((pair, _)) => pair,
+                None => break,
+            };
+            let decoder: HashMap<Rank, Vec<u8>> =
+                ranks.iter().map(|(k, v)| (*v, k.clone())).collect();
+            let (left, right) = (&decoder[&best.0], &decoder[&best.1]);
+            let merged = [left.as_slice(), right.as_slice()].concat();
+            for word in &mut words {
+                let mut i = 0;
+                while i + 1 < word.len() {
+                    if &word[i] == left && &word[i + 1] == right {
+                        word[i] = merged.clone();
+                        word.remove(i + 1);
+                    }
+                    i += 1;
+                }
+            }
+            let rank = ranks.len() as Rank;
+            ranks.entry(merged).or_insert(rank);
+        }
+        ranks
+    }
+
+    const TEXTS: &[&str] = &[
+        "hello world, hello there. the world is a big place",
+        "aaaaaaaaaa aaaa aaa a ababab abab",
+        "  indented\n\n\tand tabbed  \n",
+        "héllo wörld 日本語 日本",
+    ];
+
+    #[test]
+    fn test_train_matches_slow() {
+        for vocab_size in [256, 260, 300, 350] {
+            let (ranks, specials) = train_bpe(TEXTS, CL100K_PATTERN, [], vocab_size).unwrap();
+            assert!(specials.is_empty());
+            assert_eq!(ranks, train_slow(TEXTS, CL100K_PATTERN, vocab_size));
+        }
+    }
+
+    #[test]
+    fn test_train_deterministic() {
+        let reversed: Vec<&str> = TEXTS.iter().rev().copied().collect();
+        assert_eq!(
+            train_bpe(TEXTS, CL100K_PATTERN, [], 320).unwrap(),
+            train_bpe(reversed, CL100K_PATTERN, [], 320).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_train_special_tokens() {
+        let texts = ["hello<|endoftext|>hello<|endoftext|>hello world"];
+        let specials = ["<|endoftext|>".to_string()];
+        let (ranks, special_ranks) = train_bpe(texts, CL100K_PATTERN, specials, 300).unwrap();
+        // Runs out of pairs before reaching the vocab size
+        assert!(ranks.len() < 299);
+        assert!(!ranks
+            .keys()
+            .any(|token| token.len() > 1 && token.contains(&b'|')));
+        assert_eq!(special_ranks["<|endoftext|>"], ranks.len() as Rank);
+
+        let bpe = CoreBPE::new(ranks, special_ranks, CL100K_PATTERN).unwrap();
+        let allowed_special = ["<|endoftext|>"].into_iter().collect();
+        let tokens = bpe.encode(texts[0], &allowed_special);
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(bpe.decode_bytes(&tokens).unwrap(), texts[0].as_bytes());
+
+        assert!(matches!(
+            train_bpe(texts, CL100K_PATTERN, ["<|endoftext|>".to_string()], 256),
+            Err(TrainError::VocabSizeTooSmall { min: 257, .. })
+        ));
+        assert!(matches!(
+            train_bpe(texts, "(", [], 300),
+            Err(TrainError::InvalidPattern(_))
+        ));
+        assert!(matches!(
+            train_bpe(texts, CL100K_PATTERN, [String::new()], 300),
+            Err(TrainError::UnsupportedSpecialTokens(_))
+        ));
+    }
+
+    #[test]
+    fn test_extend() {
+        let (ranks, _) = train_bpe(TEXTS, CL100K_PATTERN, [], 300).unwrap();
+        let special_tokens: HashMap<String, Rank> =
+            [("<|endoftext|>".to_string(), 310)].into_iter().collect();
+        let texts = ["methylcyclohexane ethylcyclohexane cyclohexanone <|endoftext|>"; 3];
+        let extended = extend_bpe(texts, CL100K_PATTERN, &ranks, &special_tokens, 20).unwrap();
+
+        assert_eq!(extended.len(), ranks.len() + 20);
+        for (token, rank) in &ranks {
+            assert_eq!(extended[token], *rank);
+        }
+        let mut new_ranks: Vec<Rank> = extended
+            .iter()
+            .filter(|(token, _)| !ranks.contains_key(*token))
+            .map(|(_, rank)| *rank)
+            .collect();
+        new_ranks.sort();
+        assert_eq!(new_ranks, (311..331).collect::<Vec<_>>());
+
+        let old = CoreBPE::new(ranks, special_tokens.clone(), CL100K_PATTERN).unwrap();
+        let new = CoreBPE::new(extended, special_tokens, CL100K_PATTERN).unwrap();
+        let tokens = old.encode_ordinary(TEXTS[0]);
+        assert_eq!(
+            new.decode_bytes(&tokens).unwrap(),
+            old.decode_bytes(&tokens).unwrap()
+        );
+        let text = "cyclohexane";
+        assert!(new.encode_ordinary(text).len() < old.encode_ordinary(text).len());
+        assert_eq!(
+            new.decode_bytes(&new.encode_ordinary(text)).unwrap(),
+            text.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_extend_matches_train() {
+        // Continuing on the same texts picks up where training left off
+        let (ranks, _) = train_bpe(TEXTS, CL100K_PATTERN, [], 300).unwrap();
+        let extended = extend_bpe(TEXTS, CL100K_PATTERN, &ranks, &HashMap::default(), 30).unwrap();
+        let (expected, _) = train_bpe(TEXTS, CL100K_PATTERN, [], 330).unwrap();
+        assert_eq!(extended, expected);
+
+        let mut missing_byte = ranks.clone();
+        missing_byte.remove(&vec![b'a']);
+        assert!(matches!(
+            extend_bpe(
+                TEXTS,
+                CL100K_PATTERN,
+                &missing_byte,
+                &HashMap::default(),
+                30
+            ),
+            Err(TrainError::UnsupportedVocab(_))
+        ));
+    }
+}
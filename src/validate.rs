@@ -0,0 +1,270 @@
+//! Consistency checks for vocabularies, run by `CoreBPE::new` and available on their own for
+//! checking custom vocabularies before shipping them.
+
+use std::collections::hash_map::Entry;
+use std::fmt;
+
+use fancy_regex::Regex;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::Rank;
+
+/// A problem with a vocabulary, see `validate_vocab`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VocabError {
+    /// The pattern doesn't compile.
+    InvalidPattern(String),
+    /// Every single byte needs a token, or some text can't be encoded.
+    MissingByte(u8),
+    /// Two ordinary tokens have the same rank.
+    DuplicateRank { rank: Rank, tokens: [Vec<u8>; 2] },
+    /// A special token has the rank of an ordinary token or of another special token.
+    SpecialTokenCollision {
+        special_token: String,
+        rank: Rank,
+        other: Vec<u8>,
+    },
+    /// A token of more than one byte that isn't two lower ranked tokens put together, so no
+    /// merge makes it and it's only ever produced for a piece that is exactly that token.
+    UnreachableToken { token: Vec<u8>, rank: Rank },
+}
+
+impl fmt::Display for VocabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VocabError::InvalidPattern(e) => write!(f, "invalid pattern: {}", e),
+            VocabError::MissingByte(b) => write!(f, "missing the single byte {:#04x}", b),
+            VocabError::DuplicateRank { rank, tokens } => write!(
+                f,
+                "rank {} is used by both {:?} and {:?}",
+                rank,
+                String::from_utf8_lossy(&tokens[0]),
+                String::from_utf8_lossy(&tokens[1])
+            ),
+            VocabError::SpecialTokenCollision {
+                special_token,
+                rank,
+                other,
+            } => write!(
+                f,
+                "special token {:?} has rank {}, which is also {:?}",
+                special_token,
+                rank,
+                String::from_utf8_lossy(other)
+            ),
+            VocabError::UnreachableToken { token, rank } => write!(
+                f,
+                "token {:?} with rank {} is not a merge of two lower ranked tokens",
+                String::from_utf8_lossy(token),
+                rank
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VocabError {}
+
+/// Checks that a vocabulary makes sense for `CoreBPE::new`, and returns every problem found.
+///
+/// Reachability only asks for some split into two lower ranked tokens. See `derive_merges` for
+/// which merge `byte_pair_encode` actually makes.
+pub fn validate_vocab(
+    encoder: &HashMap<Vec<u8>, Rank>,
+    special_tokens_encoder: &HashMap<String, Rank>,
+    pattern: &str,
+) -> Result<(), Vec<VocabError>> {
+    _validate_vocab(encoder, special_tokens_encoder, pattern, true)
+}
+
+/// With an explicit merge list ranks aren't merge priorities, so `check_ranks_merge` is off and
+/// `MergeList` does its own checks.
+pub(crate) fn _validate_vocab(
+    encoder: &HashMap<Vec<u8>, Rank>,
+    special_tokens_encoder: &HashMap<String, Rank>,
+    pattern: &str,
+    check_ranks_merge: bool,
+) -> Result<(), Vec<VocabError>> {
+    let mut errors = vec![];
+
+    if let Err(e) = Regex::new(pattern) {
+        errors.push(VocabError::InvalidPattern(e.to_string()));
+    }
+
+    errors.extend(
+        (0..=255u8)
+            .filter(|&b| !encoder.contains_key(&[b][..]))
+            .map(VocabError::MissingByte),
+    );
+
+    // Sorted, so that errors come out in the same order every time
+    let mut ranked: Vec<(&[u8], Rank)> = encoder.iter().map(|(k, &v)| (&k[..], v)).collect();
+    ranked.sort_unstable();
+    let mut decoder: HashMap<Rank, &[u8]> = HashMap::default();
+    let mut duplicates = vec![];
+    for &(token, rank) in &ranked {
+        match decoder.entry(rank) {
+            Entry::Occupied(other) => duplicates.push(VocabError::DuplicateRank {
+                rank,
+                tokens: [other.get().to_vec(), token.to_vec()],
+            }),
+            Entry::Vacant(entry) => {
+                entry.insert(token);
+            }
+        }
+    }
+    duplicates.sort_by_key(|e| match e {
+        VocabError::DuplicateRank { rank, .. } => *rank,
+        _ => unreachable!(),
+    });
+    errors.extend(duplicates);
+
+    errors.extend(special_token_collisions(&decoder, special_tokens_encoder));
+
+    if check_ranks_merge {
+        let mut unreachable: Vec<(Rank, &[u8])> = ranked
+            .iter()
+            .filter(|&&(token, rank)| {
+                token.len() > 1
+                    && !(1..token.len()).any(|i| {
+                        let (left, right) = token.split_at(i);
+                        matches!(
+                            (encoder.get(left), encoder.get(right)),
+                            (Some(&l), Some(&r)) if l < rank && r < rank
+                        )
+                    })
+            })
+            .map(|&(token, rank)| (rank, token))
+            .collect();
+        unreachable.sort_unstable();
+        errors.extend(
+            unreachable
+                .into_iter()
+                .map(|(rank, token)| VocabError::UnreachableToken {
+                    token: token.to_vec(),
+                    rank,
+                }),
+        );
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Special tokens with the rank of an ordinary token in `decoder` or of another special token,
+/// sorted by rank.
+pub(crate) fn special_token_collisions<T: AsRef<[u8]>>(
+    decoder: &HashMap<Rank, T>,
+    special_tokens_encoder: &HashMap<String, Rank>,
+) -> Vec<VocabError> {
+    let mut special_tokens: Vec<(Rank, &String)> = special_tokens_encoder
+        .iter()
+        .map(|(k, &v)| (v, k))
+        .collect();
+    special_tokens.sort_unstable();
+    let mut errors = vec![];
+    let mut special_decoder: HashMap<Rank, &str> = HashMap::default();
+    for (rank, special_token) in special_tokens {
+        let other = decoder
+            .get(&rank)
+            .map(|token| token.as_ref())
+            .or_else(|| special_decoder.get(&rank).map(|s| s.as_bytes()));
+        if let Some(other) = other {
+            errors.push(VocabError::SpecialTokenCollision {
+                special_token: special_token.clone(),
+                rank,
+                other: other.to_vec(),
+            });
+        }
+        special_decoder.insert(rank, special_token);
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::{validate_vocab, VocabError};
+    use crate::tests::{setup_encoder, CL100K_PATTERN};
+    use crate::{BuildError, CoreBPE, Rank};
+
+    #[test]
+    fn test_validate_vocab() {
+        let encoder = setup_encoder(&[b"ab", b"abc", b"cd", b"abcd"]);
+        let special_tokens: HashMap<String, Rank> =
+            [("<|end|>".to_string(), 1000)].into_iter().collect();
+        assert_eq!(
+            validate_vocab(&encoder, &special_tokens, CL100K_PATTERN),
+            Ok(())
+        );
+
+        let mut broken = encoder.clone();
+        broken.remove(&b"z"[..]);
+        broken.remove(&b"\x00"[..]);
+        // Only reachable through "ab" + "cd" with a lower rank than "cd"
+        broken.insert(b"abcd".to_vec(), 257);
+        broken.insert(b"xy".to_vec(), 256);
+        let special_tokens: HashMap<String, Rank> = [
+            ("<|end|>".to_string(), 256),
+            ("<|a|>".to_string(), 1000),
+            ("<|b|>".to_string(), 1000),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            validate_vocab(&broken, &special_tokens, "(unclosed"),
+            Err(vec![
+                VocabError::InvalidPattern(
+                    fancy_regex::Regex::new("(unclosed")
+                        .unwrap_err()
+                        .to_string()
+                ),
+                VocabError::MissingByte(0),
+                VocabError::MissingByte(b'z'),
+                VocabError::DuplicateRank {
+                    rank: 256,
+                    tokens: [b"ab".to_vec(), b"xy".to_vec()],
+                },
+                VocabError::DuplicateRank {
+                    rank: 257,
+                    tokens: [b"abc".to_vec(), b"abcd".to_vec()],
+                },
+                VocabError::SpecialTokenCollision {
+                    special_token: "<|end|>".to_string(),
+                    rank: 256,
+                    other: b"ab".to_vec(),
+                },
+                VocabError::SpecialTokenCollision {
+                    special_token: "<|b|>".to_string(),
+                    rank: 1000,
+                    other: b"<|a|>".to_vec(),
+                },
+                VocabError::UnreachableToken {
+                    token: b"abcd".to_vec(),
+                    rank: 257,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_core_bpe_new_validates() {
+        let mut encoder = setup_encoder(&[b"ab"]);
+        encoder.insert(b"xyz".to_vec(), 300);
+        match CoreBPE::new(encoder.clone(), [], CL100K_PATTERN) {
+            Err(BuildError::InvalidVocab(errors)) => assert_eq!(
+                errors,
+                vec![VocabError::UnreachableToken {
+                    token: b"xyz".to_vec(),
+                    rank: 300,
+                }]
+            ),
+            _ => panic!("expected InvalidVocab"),
+        }
+        // Merges decide what is reachable when they are explicit
+        assert!(CoreBPE::from_merges(encoder, [], [], CL100K_PATTERN).is_ok());
+    }
+}
@@ -1,5 +1,7 @@
 # Note that there are more actual tests, they're just not currently public :-)
 
+import json
+import pickle
 from typing import Callable
 
 import hypothesis
@@ -8,7 +10,7 @@ import pytest
 
 import tiktoken
 
-from .test_helpers import ENCODING_FACTORIES, MAX_EXAMPLES
+from .test_helpers import ENCODING_FACTORIES, MAX_EXAMPLES, SOME_ENCODING_FACTORIES
 
 
 def test_simple():
@@ -91,6 +93,105 @@ def test_encode_surrogate_pairs():
     assert enc.encode("\ud83d") == enc.encode("�")
 
 
+def test_encoding_from_merges():
+    mergeable_ranks = {bytes([i]): i for i in range(256)}
+    # Token values don't follow merge priority, so "bc" has to be merged before "ab"
+    mergeable_ranks[b"ab"] = 256
+    mergeable_ranks[b"bc"] = 257
+    enc = tiktoken.Encoding(
+        "merges",
+        pat_str=r"\S+|\s+",
+        mergeable_ranks=mergeable_ranks,
+        special_tokens={},
+        merges=[(b"b", b"c"), (b"a", b"b")],
+    )
+    assert enc.encode("abc") == [ord("a"), 257]
+    assert enc.encode("abd") == [256, ord("d")]
+    assert pickle.loads(pickle.dumps(enc)).encode("abc") == [ord("a"), 257]
+
+    with pytest.raises(ValueError):
+        tiktoken.Encoding(
+            "merges",
+            pat_str=r"\S+|\s+",
+            mergeable_ranks=mergeable_ranks,
+            special_tokens={},
+            merges=[(b"a", b"c")],
+        )
+
+
+def test_validate_vocab():
+    from tiktoken.load import validate_vocab
+
+    mergeable_ranks = {bytes([i]): i for i in range(256)}
+    mergeable_ranks[b"ab"] = 256
+    assert validate_vocab(mergeable_ranks, {"<|end|>": 300}, r"\S+|\s+") == []
+
+    del mergeable_ranks[b"z"]
+    mergeable_ranks[b"xyz"] = 257
+    problems = validate_vocab(mergeable_ranks, {"<|end|>": 256}, r"\S+|\s+")
+    assert [p["kind"] for p in problems] == [
+        "missing_byte",
+        "special_token_collision",
+        "unreachable_token",
+    ]
+    assert problems[0]["byte"] == ord("z")
+    assert problems[1]["other"] == b"ab"
+    assert problems[2]["token"] == b"xyz"
+
+    with pytest.raises(ValueError, match="missing the single byte 0x7a"):
+        tiktoken.Encoding(
+            "broken", pat_str=r"\S+|\s+", mergeable_ranks=mergeable_ranks, special_tokens={}
+        )
+
+
+def test_load_hf_tokenizer_json(tmp_path):
+    pytest.importorskip("blobfile")
+    from tiktoken.load import load_hf_tokenizer_json
+
+    # GPT-2's bytes_to_unicode, which maps " " to "Ġ"
+    printable = [b for b in range(256) if chr(b).isprintable() and chr(b) != " "]
+    byte_to_char = {b: chr(b) for b in printable}
+    byte_to_char.update(
+        (b, chr(256 + i)) for i, b in enumerate(b for b in range(256) if b not in printable)
+    )
+    vocab = {byte_to_char[b]: b for b in range(256)}
+    vocab.update({"ab": 256, "bc": 257, "Ġa": 258})
+    tokenizer_json = {
+        "added_tokens": [
+            {"id": 300, "content": "<|end|>", "lstrip": False, "rstrip": False, "special": True}
+        ],
+        "normalizer": None,
+        "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": False, "use_regex": True},
+        "decoder": {"type": "ByteLevel"},
+        "model": {"type": "BPE", "vocab": vocab, "merges": ["b c", "a b", "Ġ a"]},
+    }
+    path = tmp_path / "tokenizer.json"
+    path.write_text(json.dumps(tokenizer_json))
+    enc = tiktoken.Encoding("hf", **load_hf_tokenizer_json(str(path)))
+    # "a" + "b" comes before "Ġ" + "a", even though "Ġa" has the lower token value
+    assert enc.encode("abc ab a<|end|>", allowed_special="all") == [97, 257, 32, 256, 258, 300]
+
+    tokenizer_json["normalizer"] = {"type": "NFC"}
+    path = tmp_path / "nfc_tokenizer.json"
+    path.write_text(json.dumps(tokenizer_json))
+    with pytest.raises(ValueError, match="NFC normalizer"):
+        load_hf_tokenizer_json(str(path))
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+def test_to_hf_tokenizer_json(make_enc: Callable[[], tiktoken.Encoding]):
+    enc = make_enc()
+    tokenizer_json = json.loads(enc.to_hf_tokenizer_json())
+    assert tokenizer_json["pre_tokenizer"]["pretokenizers"][0]["pattern"]["Regex"] == enc._pat_str
+    assert {t["content"]: t["id"] for t in tokenizer_json["added_tokens"]} == enc._special_tokens
+    assert len(tokenizer_json["model"]["vocab"]) == len(enc._mergeable_ranks)
+
+    tokenizers = pytest.importorskip("tokenizers")
+    hf = tokenizers.Tokenizer.from_str(enc.to_hf_tokenizer_json())
+    text = "hello world, it's   <|endoftext|> 12345 héllo 日本語\n\n  indented"
+    assert hf.encode(text, add_special_tokens=False).ids == enc.encode(text, allowed_special="all")
+
+
 # ====================
 # Roundtrip
 # ====================
@@ -134,6 +235,72 @@ def test_single_token_roundtrip(make_enc: Callable[[], tiktoken.Encoding]):
         assert enc.encode_single_token(token_bytes) == token
 
 
+def test_decode_unknown_tokens():
+    enc = tiktoken.get_encoding("r50k_base")
+    tokens = [31373, 99999, 995]
+
+    for decode in [enc.decode, enc.decode_bytes, enc.decode_tokens_bytes]:
+        with pytest.raises(KeyError, match="Invalid token 99999 at position 1"):
+            decode(tokens)
+    with pytest.raises(KeyError, match="position 1 of batch item 1"):
+        enc.decode_batch([[995], tokens])
+    with pytest.raises(KeyError):
+        enc.streaming_decoder().push(tokens)
+
+    assert enc.decode(tokens, unknown_tokens="skip") == "hello world"
+    assert enc.decode(tokens, unknown_tokens="replace") == "hello\ufffd world"
+    assert enc.decode_bytes_batch([tokens], unknown_tokens="skip") == [b"hello world"]
+    with pytest.raises(ValueError):
+        enc.decode(tokens, unknown_tokens="ignore")
+
+
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_streaming_decoder(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+
+    decoder = enc.streaming_decoder()
+    chunks = [decoder.push([token]) for token in enc.encode_ordinary(text)]
+    assert "".join(chunks) + decoder.finish() == text
+    assert decoder.pending() == b""
+
+
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_count_tokens(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+
+    assert enc.count_tokens_ordinary(text) == len(enc.encode_ordinary(text))
+    assert enc.count_tokens(text, disallowed_special=()) == len(enc.encode_ordinary(text))
+
+
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+@hypothesis.given(text=st.text(), max_tokens=st.integers(0, 20))
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_encode_truncated(make_enc: Callable[[], tiktoken.Encoding], text, max_tokens):
+    enc = make_enc()
+    tokens = enc.encode_ordinary(text)
+
+    head, cut = enc.encode_truncated(text, max_tokens, disallowed_special=())
+    assert len(head) <= max_tokens
+    assert head == tokens[: len(head)]
+    assert enc.decode(head) == text[:cut]
+
+    tail, start = enc.encode_truncated_tail(text, max_tokens, disallowed_special=())
+    assert len(tail) <= max_tokens
+    assert tail == tokens[len(tokens) - len(tail) :]
+    assert enc.decode(tail) == text[start:]
+
+    head, tail, (cut, start) = enc.encode_truncated_middle(
+        text, max_tokens, disallowed_special=()
+    )
+    assert len(head) + len(tail) <= max_tokens
+    assert enc.decode(head) == text[:cut]
+    assert enc.decode(tail) == text[start:]
+
+
 # ====================
 # Special tokens
 # ====================
@@ -158,6 +325,33 @@ def test_special_token():
     with pytest.raises(ValueError):
         enc.encode(text, disallowed_special={"<|fim_prefix|>"})
 
+    with pytest.raises(tiktoken.DisallowedSpecialTokenError) as e:
+        enc.encode("héllo <|fim_prefix|>", allowed_special={"<|endoftext|>"})
+    assert (e.value.token, e.value.offset) == ("<|fim_prefix|>", 7)
+    with pytest.raises(tiktoken.DisallowedSpecialTokenError) as e:
+        enc.encode_batch(["hello", "héllo <|fim_prefix|>"])
+    assert (e.value.token, e.value.offset) == ("<|fim_prefix|>", 7)
+    for encode in [
+        enc.count_tokens,
+        enc.encode_with_offsets,
+        enc.encode_with_unstable,
+        lambda text: enc.encode_truncated(text, 10),
+        lambda text: enc.encode_truncated_tail(text, 1),
+        lambda text: enc.encode_truncated_middle(text, 1),
+    ]:
+        with pytest.raises(tiktoken.DisallowedSpecialTokenError) as e:
+            encode("héllo <|fim_prefix|>")
+        assert (e.value.token, e.value.offset) == ("<|fim_prefix|>", 7)
+    # Only the prefix that gets encoded is checked
+    assert enc.encode_truncated("héllo <|fim_prefix|>", 1) == enc.encode_truncated("héllo", 1)
+
+    assert enc.encode(
+        text, allowed_special="all", special_token_policy="treat_as_text"
+    ) == enc.encode_ordinary(text)
+    assert eot not in enc.encode(text, special_token_policy="allow")
+    with pytest.raises(tiktoken.DisallowedSpecialTokenError):
+        enc.encode(text, allowed_special={"<|endoftext|>"}, special_token_policy="raise")
+
     text = "<|endoftext|> hello <|fim_prefix|> there <|fim_middle|>"
     tokens = enc.encode(text, disallowed_special=())
     assert eot not in tokens
@@ -189,6 +383,36 @@ def test_special_token():
     assert fip not in tokens
     assert fim in tokens
 
+    count = enc.count_tokens(text, allowed_special={"<|fim_middle|>"}, disallowed_special=())
+    assert count == len(tokens)
+    with pytest.raises(ValueError):
+        enc.count_tokens(text, allowed_special={"<|fim_middle|>"})
+
+
+def test_with_special_tokens():
+    enc = tiktoken.get_encoding("cl100k_base")
+    chat_enc = enc.with_special_tokens(
+        "cl100k_chat", add={"<|im_start|>": 100264}, remove=["<|fim_prefix|>"]
+    )
+    assert chat_enc.name == "cl100k_chat"
+    assert chat_enc.special_tokens_set == enc.special_tokens_set - {"<|fim_prefix|>"} | {
+        "<|im_start|>"
+    }
+    assert chat_enc.max_token_value == enc.max_token_value
+    assert enc.with_special_tokens("big", add={"<|big|>": 200000}).max_token_value == 200000
+    assert enc.with_special_tokens("none").max_token_value == enc.max_token_value
+    assert chat_enc.encode("<|im_start|>hi", allowed_special="all") == [100264, 6151]
+    assert chat_enc.encode("<|fim_prefix|>") == enc.encode_ordinary("<|fim_prefix|>")
+    assert chat_enc.decode([100264]) == "<|im_start|>"
+    # The original is unchanged
+    assert "<|im_start|>" not in enc.special_tokens_set
+    assert enc.encode("<|fim_prefix|>", allowed_special="all") == [100258]
+
+    with pytest.raises(ValueError):
+        enc.with_special_tokens("broken", add={"<|im_start|>": 100257})
+    with pytest.raises(ValueError):
+        enc.with_special_tokens("broken", add={"<|im_start|>": 6151})
+
 
 @pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
 @hypothesis.given(text=st.text())
@@ -218,6 +442,15 @@ def test_batch_encode(make_enc: Callable[[], tiktoken.Encoding]):
         enc.encode_ordinary(text2),
     ]
 
+    texts = [f"document {i} " * i for i in range(100)]
+    for num_threads in [1, 4, 64]:
+        encoded = enc.encode_batch(texts, num_threads=num_threads)
+        assert encoded == [enc.encode(text) for text in texts]
+        assert enc.decode_batch(encoded, num_threads=num_threads) == texts
+
+    with pytest.raises(ValueError):
+        enc.encode_batch([text1, "<|endoftext|>"])
+
 
 @pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
 @hypothesis.given(batch=st.lists(st.text()))
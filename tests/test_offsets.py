@@ -77,3 +77,78 @@ def test_basic_offsets():
     p, o = enc.decode_with_offsets(enc.encode(prompt))
     assert p == prompt
     assert o == [0, 1]
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_encode_with_offsets(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+
+    tokens, offsets = enc.encode_with_offsets(text, disallowed_special=())
+    assert tokens == enc.encode(text, disallowed_special=())
+    assert [start for start, _ in offsets] == enc.decode_with_offsets(tokens)[1]
+
+    tokens, offsets = enc.encode_with_offsets(text, disallowed_special=(), byte_offsets=True)
+    text_bytes = text.encode("utf-8")
+    assert [text_bytes[start:end] for start, end in offsets] == enc.decode_tokens_bytes(tokens)
+
+
+def test_basic_encode_with_offsets():
+    enc = tiktoken.get_encoding("cl100k_base")
+
+    prompt = "hello world<|endoftext|> green cow"
+    tokens, offsets = enc.encode_with_offsets(prompt, allowed_special="all")
+    assert tokens == enc.encode(prompt, allowed_special="all")
+    assert offsets == [(0, 5), (5, 11), (11, 24), (24, 30), (30, 34)]
+
+    # Characters split across tokens belong to every token that contains some of their bytes
+    prompt = "我非常渴望"
+    tokens, offsets = enc.encode_with_offsets(prompt)
+    assert [prompt[start:end] for start, end in offsets] == ["我", "非", "常", "渴", "渴", "望", "望"]
+    tokens, offsets = enc.encode_with_offsets(prompt, byte_offsets=True)
+    assert offsets[0] == (0, 3)
+    assert offsets[-1][1] == len(prompt.encode("utf-8"))
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+@hypothesis.given(
+    text=st.text(),
+    max_tokens=st.integers(2, 20),
+    overlap=st.integers(0, 1),
+    boundary=st.sampled_from(["token", "piece", "newline", "sentence"]),
+)
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_chunk_text(
+    make_enc: Callable[[], tiktoken.Encoding], text, max_tokens, overlap, boundary
+):
+    enc = make_enc()
+
+    chunks = enc.chunk_text(text, max_tokens, overlap=overlap, boundary=boundary)
+    for chunk, (start, end) in chunks:
+        assert text[start:end] == chunk
+    if overlap == 0:
+        assert "".join(chunk for chunk, _ in chunks) == text
+    if chunks:
+        assert chunks[0][1][0] == 0
+        assert chunks[-1][1][1] == len(text)
+
+
+def test_basic_chunk_text():
+    enc = tiktoken.get_encoding("cl100k_base")
+
+    text = "First sentence. Second sentence!\nThird line"
+    chunks = enc.chunk_text(text, 4, boundary="sentence")
+    assert [chunk for chunk, _ in chunks] == [
+        "First sentence.",
+        " Second sentence!\n",
+        "Third line",
+    ]
+
+    # Characters split across tokens are never cut
+    text = "我非常渴望与人工智能一起工作"
+    for chunk, (start, end) in enc.chunk_text(text, 3, overlap=1, boundary="token"):
+        assert enc.decode(enc.encode(chunk)) == chunk == text[start:end]
+
+    with pytest.raises(ValueError):
+        enc.chunk_text(text, 3, overlap=3)
@@ -1,13 +1,14 @@
 from __future__ import annotations
 
 import functools
-from concurrent.futures import ThreadPoolExecutor
-from typing import AbstractSet, Collection, Literal, NoReturn, Optional, Union
+from typing import AbstractSet, Collection, Literal, Optional, Union
 
 import regex
 
 from tiktoken import _tiktoken
 
+DisallowedSpecialTokenError = _tiktoken.DisallowedSpecialTokenError
+
 
 class Encoding:
     def __init__(
@@ -18,6 +19,9 @@ class Encoding:
         mergeable_ranks: dict[bytes, int],
         special_tokens: dict[str, int],
         explicit_n_vocab: Optional[int] = None,
+        merges: Optional[list[tuple[bytes, bytes]]] = None,
+        ignore_merges: bool = False,
+        keep_unmatched: bool = False,
     ):
         """Creates an Encoding object.
 
@@ -29,16 +33,28 @@ class Encoding:
                 should have different names.
             pat_str: A regex pattern string that is used to split the input text.
             mergeable_ranks: A dictionary mapping mergeable token bytes to their ranks. The ranks
-                must correspond to merge priority.
+                must correspond to merge priority, unless `merges` is provided.
             special_tokens: A dictionary mapping special token strings to their token values.
             explicit_n_vocab: The number of tokens in the vocabulary. If provided, it is checked
                 that the number of mergeable tokens and special tokens is equal to this number.
+            merges: An ordered list of merges as pairs of token bytes, highest priority first.
+                If provided, merge priority comes from this list, and `mergeable_ranks` only
+                maps token bytes to token values.
+            ignore_merges: Only used with `merges`. If true, a piece of text that is a single
+                token in `mergeable_ranks` is encoded as that token, even if the merges wouldn't
+                produce it, like `ignore_merges` in Hugging Face tokenizers.
+            keep_unmatched: If true, text that `pat_str` doesn't match is encoded as pieces of its
+                own, like with a `Split` pre-tokenizer with `Isolated` behavior in Hugging Face
+                tokenizers. Otherwise it is dropped, so `pat_str` should match all text.
         """
         self.name = name
 
         self._pat_str = pat_str
         self._mergeable_ranks = mergeable_ranks
         self._special_tokens = special_tokens
+        self._merges = merges
+        self._ignore_merges = ignore_merges
+        self._keep_unmatched = keep_unmatched
 
         self.max_token_value = max(
             max(mergeable_ranks.values()), max(special_tokens.values(), default=0)
@@ -47,7 +63,19 @@ class Encoding:
             assert len(mergeable_ranks) + len(special_tokens) == explicit_n_vocab
             assert self.max_token_value == explicit_n_vocab - 1
 
-        self._core_bpe = _tiktoken.CoreBPE(mergeable_ranks, special_tokens, pat_str)
+        if merges is None:
+            self._core_bpe = _tiktoken.CoreBPE(
+                mergeable_ranks, special_tokens, pat_str, keep_unmatched=keep_unmatched
+            )
+        else:
+            self._core_bpe = _tiktoken.CoreBPE.from_merges(
+                mergeable_ranks,
+                merges,
+                special_tokens,
+                pat_str,
+                ignore_merges=ignore_merges,
+                keep_unmatched=keep_unmatched,
+            )
 
     def __repr__(self) -> str:
         return f"<Encoding {self.name!r}>"
@@ -78,6 +106,7 @@ class Encoding:
         *,
         allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
         disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+        special_token_policy: Optional[Literal["allow", "raise", "treat_as_text"]] = None,
     ) -> list[int]:
         """Encodes a string into tokens.
 
@@ -93,6 +122,16 @@ class Encoding:
         - Setting `allowed_special` to "all" will cause this function to treat all text
           corresponding to special tokens to be encoded as special tokens.
 
+        A disallowed special token raises `DisallowedSpecialTokenError`, a `ValueError` with the
+        `token` and the UTF-8 byte `offset` where it starts. When special tokens overlap, the one
+        that starts first wins, and then the longest one.
+
+        `special_token_policy` replaces `disallowed_special` when it is given:
+        - "allow" encodes special tokens that aren't in `allowed_special` as natural text.
+        - "raise" raises for special tokens that aren't in `allowed_special`.
+        - "treat_as_text" encodes all special tokens as natural text, whatever `allowed_special`
+          says. Use this for untrusted text: it never produces special tokens.
+
         ```
         >>> enc.encode("hello world")
         [31373, 995]
@@ -101,27 +140,28 @@ class Encoding:
         >>> enc.encode("<|endoftext|>", allowed_special="all")
         [50256]
         >>> enc.encode("<|endoftext|>")
-        # Raises ValueError
+        # Raises DisallowedSpecialTokenError
         >>> enc.encode("<|endoftext|>", disallowed_special=())
         [27, 91, 437, 1659, 5239, 91, 29]
+        >>> enc.encode("<|endoftext|>", allowed_special="all", special_token_policy="treat_as_text")
+        [27, 91, 437, 1659, 5239, 91, 29]
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if disallowed_special:
-            if not isinstance(disallowed_special, frozenset):
-                disallowed_special = frozenset(disallowed_special)
-            if match := _special_token_regex(disallowed_special).search(text):
-                raise_disallowed_special_token(match.group())
-
-        # https://github.com/PyO3/pyo3/pull/3632
-        if isinstance(allowed_special, frozenset):
-            allowed_special = set(allowed_special)
+        if special_token_policy is not None:
+            if allowed_special == "all":
+                allowed_special = self.special_tokens_set
+            try:
+                return self._core_bpe.encode_with_policy(
+                    text, set(allowed_special), special_token_policy
+                )
+            except UnicodeEncodeError:
+                text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+                return self._core_bpe.encode_with_policy(
+                    text, set(allowed_special), special_token_policy
+                )
 
         try:
-            return self._core_bpe.encode(text, allowed_special)
+            return self._core_bpe.encode_checked(text, allowed_special, disallowed_special)
         except UnicodeEncodeError:
             # BPE operates on bytes, but the regex operates on unicode. If we pass a str that is
             # invalid UTF-8 to Rust, it will rightfully complain. Here we do a quick and dirty
@@ -130,7 +170,7 @@ class Encoding:
             # string, but given that this is input we want to support, maybe that's okay.
             # Also we use errors="replace" to handle weird things like lone surrogates.
             text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
-            return self._core_bpe.encode(text, allowed_special)
+            return self._core_bpe.encode_checked(text, allowed_special, disallowed_special)
 
     def encode_ordinary_batch(self, text: list[str], *, num_threads: int = 8) -> list[list[int]]:
         """Encodes a list of strings into tokens, in parallel, ignoring special tokens.
@@ -142,9 +182,12 @@ class Encoding:
         [[31373, 995], [11274, 16390, 995]]
         ```
         """
-        encoder = functools.partial(self.encode_ordinary)
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(encoder, text))
+        try:
+            return self._core_bpe.encode_ordinary_batch(text, num_threads)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = [t.encode("utf-16", "surrogatepass").decode("utf-16", "replace") for t in text]
+            return self._core_bpe.encode_ordinary_batch(text, num_threads)
 
     def encode_batch(
         self,
@@ -163,18 +206,191 @@ class Encoding:
         [[31373, 995], [11274, 16390, 995]]
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if not isinstance(disallowed_special, frozenset):
-            disallowed_special = frozenset(disallowed_special)
-
-        encoder = functools.partial(
-            self.encode, allowed_special=allowed_special, disallowed_special=disallowed_special
+        try:
+            return self._core_bpe.encode_batch(
+                text, allowed_special, disallowed_special, num_threads
+            )
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = [t.encode("utf-16", "surrogatepass").decode("utf-16", "replace") for t in text]
+            return self._core_bpe.encode_batch(
+                text, allowed_special, disallowed_special, num_threads
+            )
+
+    def encode_with_offsets(
+        self,
+        text: str,
+        *,
+        allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
+        disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+        byte_offsets: bool = False,
+    ) -> tuple[list[int], list[tuple[int, int]]]:
+        """Encodes a string into tokens, along with the span of `text` each token came from.
+
+        Spans are (start, end) indices into `text`. If a character is split across several
+        tokens, the span of each of those tokens includes the whole character. Set `byte_offsets`
+        to get spans into `text.encode("utf-8")` instead, which never overlap.
+
+        See `encode` for more details on `allowed_special` and `disallowed_special`.
+
+        ```
+        >>> enc.encode_with_offsets("hello world")
+        ([31373, 995], [(0, 5), (5, 11)])
+        ```
+        """
+        return self._core_bpe.encode_with_offsets(
+            text, allowed_special, disallowed_special, char_offsets=not byte_offsets
+        )
+
+    def count_tokens_ordinary(self, text: str) -> int:
+        """Counts the tokens in a string, ignoring special tokens.
+
+        This is equivalent to `len(enc.encode_ordinary(text))`, but doesn't build a list of tokens.
+
+        ```
+        >>> enc.count_tokens_ordinary("hello world")
+        2
+        ```
+        """
+        try:
+            return self._core_bpe.count_tokens_ordinary(text)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.count_tokens_ordinary(text)
+
+    def count_tokens(
+        self,
+        text: str,
+        *,
+        allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
+        disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+    ) -> int:
+        """Counts the tokens in a string.
+
+        This is equivalent to `len(enc.encode(text, ...))`, but doesn't build the list of tokens.
+        See `encode` for more details on `allowed_special` and `disallowed_special`.
+
+        ```
+        >>> enc.count_tokens("hello world")
+        2
+        >>> enc.count_tokens("<|endoftext|>", allowed_special="all")
+        1
+        >>> enc.count_tokens("<|endoftext|>")
+        # Raises ValueError
+        >>> enc.count_tokens("<|endoftext|>", disallowed_special=())
+        7
+        ```
+        """
+        try:
+            return self._core_bpe.count_tokens(text, allowed_special, disallowed_special)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.count_tokens(text, allowed_special, disallowed_special)
+
+    def encode_truncated(
+        self,
+        text: str,
+        max_tokens: int,
+        *,
+        allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
+        disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+        byte_offsets: bool = False,
+    ) -> tuple[list[int], int]:
+        """Encodes the longest prefix of a string that fits in `max_tokens` tokens.
+
+        Returns the tokens, which are the first tokens of `encode(text)`, and the index in `text`
+        where the prefix ends. The cut never splits a token or a character, so fewer than
+        `max_tokens` tokens may be returned. Set `byte_offsets` to get an index into
+        `text.encode("utf-8")` instead.
+
+        See `encode` for more details on `allowed_special` and `disallowed_special`. Only the
+        prefix that gets encoded is checked for disallowed special tokens.
+
+        ```
+        >>> enc.encode_truncated("hello world", 1)
+        ([31373], 5)
+        ```
+        """
+        return self._core_bpe.encode_truncated(
+            text, max_tokens, allowed_special, disallowed_special, char_offsets=not byte_offsets
+        )
+
+    def encode_truncated_tail(
+        self,
+        text: str,
+        max_tokens: int,
+        *,
+        allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
+        disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+        byte_offsets: bool = False,
+    ) -> tuple[list[int], int]:
+        """Encodes the longest suffix of a string that fits in `max_tokens` tokens.
+
+        Returns the tokens, which are the last tokens of `encode(text)`, and the index in `text`
+        where the suffix starts. See `encode_truncated` for more details.
+
+        ```
+        >>> enc.encode_truncated_tail("hello world", 1)
+        ([995], 5)
+        ```
+        """
+        return self._core_bpe.encode_truncated_tail(
+            text, max_tokens, allowed_special, disallowed_special, char_offsets=not byte_offsets
+        )
+
+    def encode_truncated_middle(
+        self,
+        text: str,
+        max_tokens: int,
+        *,
+        allowed_special: Union[Literal["all"], AbstractSet[str]] = set(),  # noqa: B006
+        disallowed_special: Union[Literal["all"], Collection[str]] = "all",
+        byte_offsets: bool = False,
+    ) -> tuple[list[int], list[int], tuple[int, int]]:
+        """Encodes the start and the end of a string, dropping the middle to fit in `max_tokens`.
+
+        Returns the tokens of the start, the tokens of the end, and the (start, end) span of
+        `text` that was dropped, so that you can put a marker in between. The start gets the
+        larger half of the budget. If all of `text` fits, its tokens are returned as the start
+        and the dropped span is empty. See `encode_truncated` for more details.
+
+        ```
+        >>> enc.encode_truncated_middle("hello world, how are you", 2)
+        ([31373], [345], (5, 20))
+        ```
+        """
+        return self._core_bpe.encode_truncated_middle(
+            text, max_tokens, allowed_special, disallowed_special, char_offsets=not byte_offsets
+        )
+
+    def chunk_text(
+        self,
+        text: str,
+        max_tokens: int,
+        *,
+        overlap: int = 0,
+        boundary: Literal["token", "piece", "newline", "sentence"] = "piece",
+        byte_offsets: bool = False,
+    ) -> list[tuple[str, tuple[int, int]]]:
+        """Splits a string into chunks of at most `max_tokens` tokens, ignoring special tokens.
+
+        Each chunk repeats the last `overlap` tokens of the chunk before it. Chunks end at the
+        last `boundary` that fits, falling back to the boundary between two regex pieces, and then
+        to any token boundary. Chunks are never cut inside a character.
+
+        Returns the chunks and their (start, end) spans in `text`. Set `byte_offsets` to get spans
+        into `text.encode("utf-8")` instead.
+
+        ```
+        >>> enc.chunk_text("hello world. goodbye world.", 3, boundary="sentence")
+        [('hello world.', (0, 12)), (' goodbye world.', (12, 27))]
+        ```
+        """
+        return self._core_bpe.chunk_text(
+            text, max_tokens, overlap, boundary, char_offsets=not byte_offsets
         )
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(encoder, text))
 
     def encode_with_unstable(
         self,
@@ -201,17 +417,7 @@ class Encoding:
         >>> assert all(enc.decode_bytes(stable_tokens + seq).startswith(text.encode()) for seq in completions)
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if disallowed_special:
-            if not isinstance(disallowed_special, frozenset):
-                disallowed_special = frozenset(disallowed_special)
-            if match := _special_token_regex(disallowed_special).search(text):
-                raise_disallowed_special_token(match.group())
-
-        return self._core_bpe.encode_with_unstable(text, allowed_special)
+        return self._core_bpe.encode_with_unstable(text, allowed_special, disallowed_special)
 
     def encode_single_token(self, text_or_bytes: Union[str, bytes]) -> int:
         """Encodes text corresponding to a single token to its token value.
@@ -233,29 +439,48 @@ class Encoding:
     # Decoding
     # ====================
 
-    def decode_bytes(self, tokens: list[int]) -> bytes:
+    def decode_bytes(
+        self,
+        tokens: list[int],
+        *,
+        unknown_tokens: Literal["raise", "skip", "replace"] = "raise",
+    ) -> bytes:
         """Decodes a list of tokens into bytes.
 
+        Raises `KeyError` naming the token and its position if a token is not in the vocabulary.
+        Pass `unknown_tokens="skip"` to leave such tokens out, or `unknown_tokens="replace"` to
+        decode them to U+FFFD.
+
         ```
         >>> enc.decode_bytes([31373, 995])
         b'hello world'
+        >>> enc.decode_bytes([31373, 999999, 995], unknown_tokens="skip")
+        b'hello world'
         ```
         """
-        return self._core_bpe.decode_bytes(tokens)
+        return self._core_bpe.decode_bytes(tokens, unknown_tokens)
 
-    def decode(self, tokens: list[int], errors: str = "replace") -> str:
+    def decode(
+        self,
+        tokens: list[int],
+        errors: str = "replace",
+        *,
+        unknown_tokens: Literal["raise", "skip", "replace"] = "raise",
+    ) -> str:
         """Decodes a list of tokens into a string.
 
         WARNING: the default behaviour of this function is lossy, since decoded bytes are not
         guaranteed to be valid UTF-8. You can control this behaviour using the `errors` parameter,
         for instance, setting `errors=strict`.
 
+        Tokens that are not in the vocabulary are handled as in `decode_bytes`.
+
         ```
         >>> enc.decode([31373, 995])
         'hello world'
         ```
         """
-        return self._core_bpe.decode_bytes(tokens).decode("utf-8", errors=errors)
+        return self._core_bpe.decode_bytes(tokens, unknown_tokens).decode("utf-8", errors=errors)
 
     def decode_single_token_bytes(self, token: int) -> bytes:
         """Decodes a token into bytes.
@@ -277,8 +502,16 @@ class Encoding:
         Useful for visualising tokenisation.
         >>> enc.decode_tokens_bytes([31373, 995])
         [b'hello', b' world']
+
+        Raises `KeyError` naming the token and its position if a token is not in the vocabulary.
         """
-        return [self.decode_single_token_bytes(token) for token in tokens]
+        ret = []
+        for position, token in enumerate(tokens):
+            try:
+                ret.append(self._core_bpe.decode_single_token_bytes(token))
+            except KeyError:
+                raise KeyError(f"Invalid token {token} at position {position}") from None
+        return ret
 
     def decode_with_offsets(self, tokens: list[int]) -> tuple[str, list[int]]:
         """Decodes a list of tokens into a string and a list of offsets.
@@ -305,18 +538,50 @@ class Encoding:
         text = b"".join(token_bytes).decode("utf-8", errors="strict")
         return text, offsets
 
+    def streaming_decoder(self) -> "_tiktoken.StreamingDecoder":
+        """Returns a decoder for tokens that arrive a few at a time.
+
+        Unlike calling `decode` on each token, this never splits a character: bytes that are not
+        yet complete UTF-8 are held back until the rest of the character arrives. Call `finish`
+        at the end of the stream to get anything that is left over.
+
+        ```
+        >>> decoder = enc.streaming_decoder()
+        >>> decoder.push([9468, 236])  # the first three bytes of "🎉"
+        ''
+        >>> decoder.push([231])
+        '🎉'
+        ```
+        """
+        return self._core_bpe.streaming_decoder()
+
     def decode_batch(
-        self, batch: list[list[int]], *, errors: str = "replace", num_threads: int = 8
+        self,
+        batch: list[list[int]],
+        *,
+        errors: str = "replace",
+        unknown_tokens: Literal["raise", "skip", "replace"] = "raise",
+        num_threads: int = 8,
     ) -> list[str]:
-        """Decodes a batch (list of lists of tokens) into a list of strings."""
-        decoder = functools.partial(self.decode, errors=errors)
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(decoder, batch))
+        """Decodes a batch (list of lists of tokens) into a list of strings.
 
-    def decode_bytes_batch(self, batch: list[list[int]], *, num_threads: int = 8) -> list[bytes]:
+        Tokens that are not in the vocabulary are handled as in `decode_bytes`, and the error
+        also names the batch item.
+        """
+        return [
+            b.decode("utf-8", errors=errors)
+            for b in self._core_bpe.decode_batch(batch, num_threads, unknown_tokens)
+        ]
+
+    def decode_bytes_batch(
+        self,
+        batch: list[list[int]],
+        *,
+        unknown_tokens: Literal["raise", "skip", "replace"] = "raise",
+        num_threads: int = 8,
+    ) -> list[bytes]:
         """Decodes a batch (list of lists of tokens) into a list of bytes."""
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(self.decode_bytes, batch))
+        return self._core_bpe.decode_batch(batch, num_threads, unknown_tokens)
 
     # ====================
     # Miscellaneous
@@ -326,6 +591,63 @@ class Encoding:
         """Returns the list of all token byte values."""
         return self._core_bpe.token_byte_values()
 
+    def to_hf_tokenizer_json(self) -> str:
+        """Returns a Hugging Face `tokenizer.json` that encodes the same way as this encoding.
+
+        Hugging Face tokenizers always match special tokens, so this is the same as
+        `encode(text, allowed_special="all")`.
+
+        ```
+        >>> with open("tokenizer.json", "w") as f:
+        ...     f.write(enc.to_hf_tokenizer_json())
+        >>> tokenizers.Tokenizer.from_file("tokenizer.json").encode("hello world").ids
+        [31373, 995]
+        ```
+        """
+        return self._core_bpe.to_hf_tokenizer_json()
+
+    def with_special_tokens(
+        self,
+        name: str,
+        *,
+        add: Optional[dict[str, int]] = None,
+        remove: Collection[str] = (),
+    ) -> Encoding:
+        """Returns a new encoding with the special tokens in `remove` removed and those in `add`
+        added.
+
+        This shares the mergeable ranks with this encoding instead of building them again, so it
+        is much faster than creating a new `Encoding`. Raises a ValueError if a special token
+        would have the same value as another token.
+
+        ```
+        >>> chat_enc = enc.with_special_tokens(
+        ...     "cl100k_chat", add={"<|im_start|>": 100264, "<|im_end|>": 100265}
+        ... )
+        >>> chat_enc.encode("<|im_start|>", allowed_special="all")
+        [100264]
+        ```
+        """
+        add = add or {}
+        core_bpe = self._core_bpe.with_special_tokens(add, list(remove))
+
+        special_tokens = {
+            token: rank for token, rank in self._special_tokens.items() if token not in remove
+        }
+        special_tokens.update(add)
+
+        # Not copy.copy, which would share __dict__ with registered encodings, see __setstate__
+        ret = type(self).__new__(type(self))
+        ret.__dict__.update(self.__dict__)
+        ret.__dict__.pop("special_tokens_set", None)
+        ret.name = name
+        ret._special_tokens = special_tokens
+        ret._core_bpe = core_bpe
+        # Without scanning the mergeable ranks again. Removing the special token with the highest
+        # value doesn't lower this, which only leaves that value unused.
+        ret.max_token_value = max([self.max_token_value, *add.values()])
+        return ret
+
     @property
     def eot_token(self) -> int:
         return self._special_tokens["<|endoftext|>"]
@@ -374,12 +696,18 @@ class Encoding:
         # As an optimisation, pickle registered encodings by reference
         if self is tiktoken.registry.ENCODINGS.get(self.name):
             return self.name
-        return {
+        state = {
             "name": self.name,
             "pat_str": self._pat_str,
             "mergeable_ranks": self._mergeable_ranks,
             "special_tokens": self._special_tokens,
         }
+        if self._merges is not None:
+            state["merges"] = self._merges
+            state["ignore_merges"] = self._ignore_merges
+        if self._keep_unmatched:
+            state["keep_unmatched"] = True
+        return state
 
     def __setstate__(self, value: object) -> None:
         import tiktoken.registry
@@ -389,19 +717,3 @@ class Encoding:
             return
         self.__init__(**value)
 
-
-@functools.lru_cache(maxsize=128)
-def _special_token_regex(tokens: frozenset[str]) -> "regex.Pattern[str]":
-    inner = "|".join(regex.escape(token) for token in tokens)
-    return regex.compile(f"({inner})")
-
-
-def raise_disallowed_special_token(token: str) -> NoReturn:
-    raise ValueError(
-        f"Encountered text corresponding to disallowed special token {token!r}.\n"
-        "If you want this text to be encoded as a special token, "
-        f"pass it to `allowed_special`, e.g. `allowed_special={{{token!r}, ...}}`.\n"
-        f"If you want this text to be encoded as normal text, disable the check for this token "
-        f"by passing `disallowed_special=(enc.special_tokens_set - {{{token!r}}})`.\n"
-        "To disable this check for all special tokens, pass `disallowed_special=()`.\n"
-    )
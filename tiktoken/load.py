@@ -6,7 +6,7 @@ import json
 import os
 import tempfile
 import uuid
-from typing import Optional
+from typing import Any, Optional
 
 import requests
 
@@ -140,12 +140,71 @@ def dump_tiktoken_bpe(bpe_ranks: dict[bytes, int], tiktoken_bpe_file: str) -> No
             f.write(base64.b64encode(token) + b" " + str(rank).encode() + b"\n")
 
 
+def validate_vocab(
+    bpe_ranks: dict[bytes, int], special_tokens: dict[str, int], pat_str: str
+) -> list[dict[str, Any]]:
+    """Checks a vocabulary for the problems that `Encoding` would refuse to load.
+
+    These are: a pattern that doesn't compile, missing single bytes, two tokens with the same
+    rank, special tokens with the rank of another token, and tokens that aren't a merge of two
+    lower ranked tokens. Returns a dict for each problem, with its `kind`, a `message` and the
+    tokens and ranks involved. An empty list means the vocabulary is fine.
+    """
+    from tiktoken import _tiktoken
+
+    return _tiktoken.validate_vocab(bpe_ranks, special_tokens, pat_str)
+
+
+def derive_merges(bpe_ranks: dict[bytes, int]) -> tuple[list[tuple[bytes, bytes]], list[bytes]]:
+    """Rebuilds the merge that made each token, which `.tiktoken` files don't record.
+
+    Returns the merges in rank order, and the tokens that aren't a merge of two lower ranked
+    tokens, e.g. because they were added to the vocabulary by hand.
+    """
+    from tiktoken import _tiktoken
+
+    return _tiktoken.derive_merges(bpe_ranks)
+
+
+def dump_merges_txt(bpe_ranks: dict[bytes, int], merges_txt_file: str) -> list[bytes]:
+    """Writes the merges of `bpe_ranks` in the format of data gym's `vocab.bpe` (`merges.txt`).
+
+    Returns the tokens that couldn't be derived, which are left out of the file.
+    """
+    try:
+        import blobfile
+    except ImportError as e:
+        raise ImportError(
+            "blobfile is not installed. Please install it by running `pip install blobfile`."
+        ) from e
+    from tiktoken import _tiktoken
+
+    merges, underivable = _tiktoken.derive_merges(bpe_ranks)
+    with blobfile.BlobFile(merges_txt_file, "wb") as f:
+        f.write(_tiktoken.merges_txt(merges))
+    return underivable
+
+
 def load_tiktoken_bpe(
     tiktoken_bpe_file: str, expected_hash: Optional[str] = None
 ) -> dict[bytes, int]:
     # NB: do not add caching to this function
+    from tiktoken import _tiktoken
+
     contents = read_file_cached(tiktoken_bpe_file, expected_hash)
-    return {
-        base64.b64decode(token): int(rank)
-        for token, rank in (line.split() for line in contents.splitlines() if line)
-    }
+    return _tiktoken.load_tiktoken_bpe(contents)
+
+
+def load_hf_tokenizer_json(
+    tokenizer_json_file: str, expected_hash: Optional[str] = None
+) -> dict[str, Any]:
+    """Converts a Hugging Face `tokenizer.json` with a byte-level BPE model.
+
+    Returns the keyword arguments for `Encoding`, so that
+    `Encoding(name, **load_hf_tokenizer_json(path))` encodes like the Hugging Face tokenizer.
+    Raises ValueError if the file uses features that tiktoken can't represent, like normalizers.
+    """
+    from tiktoken import _tiktoken
+
+    contents = read_file_cached(tokenizer_json_file, expected_hash)
+    return _tiktoken.load_hf_tokenizer_json(contents)